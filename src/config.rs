@@ -3,38 +3,173 @@ use std::path::Path;
 use config::{Config, ConfigError, File};
 use serde::export::fmt::Debug;
 
-use super::udevdetect::USBHIDClass;
-use super::proxydev::SimpleDeviceClass;
+use super::udevdetect::{InputBus, USBHIDClass};
+use super::proxydev::{AbsAxisSpec, GrabMode, KeyProfile, SimpleDeviceClass};
 
 #[derive(Debug, Deserialize)]
 pub struct SelfConfig {
     pub device: Vec<Device>,
     // pub grab_devices: Vec<InputDevice>,
     pub log_level: String,
+    pub profile_dir: Option<String>,
+    pub control: Option<ControlConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Unix socket for runtime control of proxy devices
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ControlConfig {
+    pub path: String,
+    pub mode: Option<u32>,
+    pub layout_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub enum Device {
     Simple {
         name: String,
         vendor: u16,
         model: u16,
         class: SimpleDeviceClass,
+        keys: Option<KeyProfile>,
+        axes: Option<Vec<AbsAxisSpec>>,
         selector: Option<Vec<DeviceSelector>>,
+        exclude: Option<Vec<DeviceSelector>>,
+        frame_size: Option<usize>,
+        frame_timeout: Option<u64>,
+        grab_toggle: Option<Vec<String>>,
+    },
+    /// Same as `Simple`, but with several virtual devices, `switch` chord
+    /// selects which one of them receives events
+    Router {
+        name: String,
+        vendor: u16,
+        model: u16,
+        class: SimpleDeviceClass,
+        keys: Option<KeyProfile>,
+        axes: Option<Vec<AbsAxisSpec>>,
+        selector: Option<Vec<DeviceSelector>>,
+        exclude: Option<Vec<DeviceSelector>>,
+        frame_size: Option<usize>,
+        frame_timeout: Option<u64>,
+        grab_toggle: Option<Vec<String>>,
+        outputs: Vec<String>,
+        switch: Vec<String>,
     },
 }
 
-#[derive(Debug, Deserialize)]
+impl Device {
+    pub fn name(&self) -> &str {
+        match self {
+            Device::Simple{name, ..} => name,
+            Device::Router{name, ..} => name,
+        }
+    }
+
+    pub fn selector(&self) -> &Option<Vec<DeviceSelector>> {
+        match self {
+            Device::Simple{selector, ..} => selector,
+            Device::Router{selector, ..} => selector,
+        }
+    }
+
+    pub fn exclude(&self) -> &Option<Vec<DeviceSelector>> {
+        match self {
+            Device::Simple{exclude, ..} => exclude,
+            Device::Router{exclude, ..} => exclude,
+        }
+    }
+
+    /// Whether definitions differ only by selectors, so running proxy could be kept
+    pub fn same_proxy(&self, other: &Device) -> bool {
+        let (mut a, mut b) = (self.clone(), other.clone());
+        for dev in [&mut a, &mut b] {
+            match dev {
+                Device::Simple{selector, exclude, ..} | Device::Router{selector, exclude, ..} => {
+                    *selector = None;
+                    *exclude = None;
+                },
+            }
+        }
+        a == b
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DeviceSelector {
     USBID{
         vendor: u16,
-        model: u16
+        model: u16,
+        grab: Option<GrabMode>,
     },
     USBIDClass{
         vendor: u16,
         model: u16,
         class: USBHIDClass,
+        grab: Option<GrabMode>,
+    },
+    /// Device name matched against glob, or regex if `regex` is set
+    Name{
+        name: String,
+        regex: Option<bool>,
+        grab: Option<GrabMode>,
+    },
+    /// Physical port, `ID_PATH` or `ID_PATH_TAG` of device, with `prefix` set
+    /// devices behind hub plugged into the port match too
+    Port{
+        path: String,
+        prefix: Option<bool>,
+        grab: Option<GrabMode>,
+    },
+    /// Serial number (`ID_SERIAL`, `ID_SERIAL_SHORT` or `uniq`), optionally
+    /// of given vendor:model only
+    Serial{
+        serial: String,
+        vendor: Option<u16>,
+        model: Option<u16>,
+        grab: Option<GrabMode>,
     },
+    /// Bus, vendor, product and version of input device, unset ones match
+    /// anything. Unlike `USBID` matches Bluetooth and built-in devices too.
+    InputID{
+        bus: Option<InputBus>,
+        vendor: Option<u16>,
+        product: Option<u16>,
+        version: Option<u16>,
+        grab: Option<GrabMode>,
+    },
+    /// Matches devices that match all of `selectors`, their own `grab` is ignored
+    All{
+        selectors: Vec<DeviceSelector>,
+        grab: Option<GrabMode>,
+    },
+    /// Matches devices that match any of `selectors`, their own `grab` is ignored
+    Any{
+        selectors: Vec<DeviceSelector>,
+        grab: Option<GrabMode>,
+    },
+    /// Matches devices that do not match `selector`
+    Not{
+        selector: Box<DeviceSelector>,
+        grab: Option<GrabMode>,
+    },
+}
+
+impl DeviceSelector {
+    /// How devices matched by selector are grabbed
+    pub fn grab(&self) -> GrabMode {
+        match self {
+            DeviceSelector::USBID{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::USBIDClass{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::Name{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::Port{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::Serial{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::InputID{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::All{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::Any{grab, ..} => grab.unwrap_or_default(),
+            DeviceSelector::Not{grab, ..} => grab.unwrap_or_default(),
+        }
+    }
 }
 
 pub fn read_config<P: AsRef<Path> + Debug + ToString>(path: P) -> Result<SelfConfig, ConfigError> {
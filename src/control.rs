@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel;
+use crossbeam::channel::Sender;
+use nix::sys::stat::{self, Mode};
+use serde_json::{json, Value};
+
+use super::proxydev::{GrabMode, InjectSpec, Layout, Simple};
+
+/// Keyboard layout used to type text if request does not set one
+const DEFAULT_LAYOUT: &str = "us";
+/// Delay after each typed character in milliseconds
+const DEFAULT_TYPE_DELAY: u64 = 10;
+
+/// Running proxy devices by their names
+pub type Registry = Arc<Mutex<HashMap<String, Arc<Simple>>>>;
+
+/// Request to re-read config, with channel for its result if anyone waits for it
+pub type ReloadRequest = Option<Sender<io::Result<()>>>;
+
+/// State shared by control connections
+#[derive(Clone)]
+pub struct Context {
+    pub registry: Registry,
+    /// Keyboard layouts are searched here before ones shipped with the project
+    pub layout_dir: Option<String>,
+    pub reload: Sender<ReloadRequest>,
+}
+
+/// Request to control socket, one JSON object per line, e.g.
+/// `{"command": "remove_source", "proxy": "EvdevProxyMouse", "path": "/dev/input/event5"}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Proxies with their outputs and sources
+    List,
+    AddSource {
+        proxy: String,
+        path: String,
+        grab: Option<GrabMode>,
+    },
+    RemoveSource {
+        proxy: String,
+        path: String,
+    },
+    PauseSource {
+        proxy: String,
+        path: String,
+    },
+    ResumeSource {
+        proxy: String,
+        path: String,
+    },
+    /// Grabs sources of proxy or hands them back to host, toggles if `grab` is not set
+    Grab {
+        proxy: String,
+        grab: Option<bool>,
+    },
+    /// Passes events of router to output given by its name or index
+    Switch {
+        proxy: String,
+        output: String,
+    },
+    /// Writes synthetic events, e.g. `"keys": ["KEY_LEFTCTRL", "KEY_LEFTALT", "KEY_DELETE"]`
+    Inject {
+        proxy: String,
+        #[serde(flatten)]
+        spec: InjectSpec,
+    },
+    /// Types text using keyboard layout, answers when all of it is typed
+    Type {
+        proxy: String,
+        text: String,
+        layout: Option<String>,
+        delay: Option<u64>,
+    },
+    /// Path of the virtual device that currently receives events
+    DevPath {
+        proxy: String,
+    },
+    /// Re-reads config, answers when changes are applied
+    Reload,
+}
+
+/// Listens for control connections on Unix socket at `path`
+pub fn listen(path: &str, mode: Option<u32>, ctx: Context) -> io::Result<()> {
+    // Socket of previous instance is left behind if it wasn't stopped cleanly,
+    // anything else at the path is not ours to remove
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{:}' exists and is not a socket", path))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    // Socket is created accessible to owner only and opened up to `mode`
+    // afterwards, so it is never reachable with wider permissions
+    let old_mask = stat::umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    stat::umask(old_mask);
+    let listener = listener?;
+    if let Some(mode) = mode {
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+    }
+    info!("Listening for control connections on '{:?}'", path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let ctx = ctx.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &ctx) {
+                            debug!("Control connection failed: {:?}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("Failed to accept control connection: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, ctx: &Context) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("Got control request: {:?}", request);
+                handle(request, ctx)
+            },
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+        let response = match response {
+            Ok(result) => json!({"ok": true, "result": result}),
+            Err(e) => json!({"ok": false, "error": e.to_string()}),
+        };
+        writeln!(writer, "{:}", response)?;
+    }
+    Ok(())
+}
+
+fn proxy(registry: &Registry, name: &str) -> io::Result<Arc<Simple>> {
+    registry.lock().unwrap().get(name).cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no proxy device '{:}'", name)))
+}
+
+fn describe(pd: &Simple) -> Value {
+    json!({
+        "name": pd.name(),
+        "dev_path": pd.dev_path(),
+        "grabbed": pd.is_grabbed(),
+        "active": pd.active_output(),
+        "outputs": pd.outputs().iter()
+            .map(|(name, path)| json!({"name": name, "dev_path": path}))
+            .collect::<Vec<Value>>(),
+        "num_sources": pd.num_sources(),
+        "sources": pd.sources().iter()
+            .map(|(path, paused)| json!({"path": path, "paused": paused}))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+fn handle(request: Request, ctx: &Context) -> io::Result<Value> {
+    let registry = &ctx.registry;
+    match request {
+        Request::List => {
+            let proxies: Vec<Arc<Simple>> = registry.lock().unwrap().values().cloned().collect();
+            Ok(Value::Array(proxies.iter().map(|pd| describe(pd)).collect()))
+        },
+        Request::AddSource{proxy: name, path, grab} => {
+            proxy(registry, &name)?.add_source_dev(&path, grab.unwrap_or_default())?;
+            Ok(Value::Null)
+        },
+        Request::RemoveSource{proxy: name, path} => {
+            proxy(registry, &name)?.remove_source_dev(&path)?;
+            Ok(Value::Null)
+        },
+        Request::PauseSource{proxy: name, path} => {
+            proxy(registry, &name)?.pause_source(&path, true)?;
+            Ok(Value::Null)
+        },
+        Request::ResumeSource{proxy: name, path} => {
+            proxy(registry, &name)?.pause_source(&path, false)?;
+            Ok(Value::Null)
+        },
+        Request::Grab{proxy: name, grab} => {
+            let pd = proxy(registry, &name)?;
+            let grab = grab.unwrap_or(!pd.is_grabbed());
+            pd.set_grab(grab);
+            Ok(json!(grab))
+        },
+        Request::Switch{proxy: name, output} => {
+            let pd = proxy(registry, &name)?;
+            let idx = match pd.outputs().iter().position(|(name, _)| *name == output) {
+                Some(idx) => idx,
+                None => output.parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("no output '{:}' of '{:}'", output, name)))?,
+            };
+            pd.switch_output(idx)?;
+            Ok(json!(idx))
+        },
+        Request::Inject{proxy: name, spec} => {
+            proxy(registry, &name)?.inject(&spec)?;
+            Ok(Value::Null)
+        },
+        Request::Type{proxy: name, text, layout, delay} => {
+            let pd = proxy(registry, &name)?;
+            let layout = Layout::load(layout.as_deref().unwrap_or(DEFAULT_LAYOUT), ctx.layout_dir.as_deref())?;
+            pd.type_text(&text, &layout, Duration::from_millis(delay.unwrap_or(DEFAULT_TYPE_DELAY)))?;
+            Ok(Value::Null)
+        },
+        Request::DevPath{proxy: name} => {
+            Ok(json!(proxy(registry, &name)?.dev_path()))
+        },
+        Request::Reload => {
+            let (tx, rx) = channel::bounded(1);
+            ctx.reload.send(Some(tx)).unwrap();
+            rx.recv().unwrap()?;
+            Ok(Value::Null)
+        },
+    }
+}
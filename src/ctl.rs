@@ -0,0 +1,238 @@
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+
+use clap::{App, Arg, ArgMatches};
+use serde_json::{json, Value};
+
+/// `ctl` subcommand, client of control socket
+pub fn subcommand() -> App<'static> {
+    let proxy = || Arg::new("proxy").about("Name of proxy device").required(true);
+    let path = || Arg::new("path").about("Path of source device, e.g. /dev/input/event5").required(true);
+    App::new("ctl")
+        .about("Controls running daemon through its control socket")
+        .arg(Arg::new("socket")
+            .short('s')
+            .long("socket")
+            .about("Path to control socket, taken from config by default")
+            .takes_value(true))
+        .arg(Arg::new("json")
+            .long("json")
+            .about("Print daemon responses as JSON"))
+        .subcommand(App::new("status")
+            .about("Lists proxy devices"))
+        .subcommand(App::new("sources")
+            .about("Lists source devices of proxy")
+            .arg(proxy()))
+        .subcommand(App::new("attach")
+            .about("Adds source device to proxy")
+            .arg(proxy())
+            .arg(path())
+            .arg(Arg::new("grab")
+                .long("grab")
+                .about("Grab mode of device")
+                .possible_values(&["exclusive", "shared", "try"])
+                .takes_value(true)))
+        .subcommand(App::new("detach")
+            .about("Removes source device from proxy")
+            .arg(proxy())
+            .arg(path()))
+        .subcommand(App::new("grab")
+            .about("Grabs source devices of proxy back from host")
+            .arg(proxy()))
+        .subcommand(App::new("ungrab")
+            .about("Hands source devices of proxy back to host")
+            .arg(proxy()))
+        .subcommand(App::new("switch")
+            .about("Passes events of router to another output")
+            .arg(proxy())
+            .arg(Arg::new("output").about("Output name or index").required(true)))
+        .subcommand(App::new("reload")
+            .about("Re-reads config of daemon"))
+        .subcommand(App::new("inject")
+            .about("Writes synthetic events to proxy device")
+            .arg(proxy())
+            .arg(Arg::new("keys")
+                .long("keys")
+                .about("Key chord, e.g. KEY_LEFTCTRL,KEY_C")
+                .takes_value(true)
+                .use_delimiter(true))
+            .arg(Arg::new("event")
+                .long("event")
+                .about("Raw event TYPE:CODE:VALUE, e.g. EV_KEY:KEY_A:1")
+                .takes_value(true)
+                .multiple_occurrences(true))
+            .arg(Arg::new("rel")
+                .long("rel")
+                .about("Relative motion AXIS=VALUE, e.g. REL_X=10,REL_Y=-5")
+                .takes_value(true)
+                .use_delimiter(true)))
+        .subcommand(App::new("type")
+            .about("Types text on proxy device, reads it from stdin if not given")
+            .arg(proxy())
+            .arg(Arg::new("text").about("Text to type"))
+            .arg(Arg::new("layout")
+                .long("layout")
+                .about("Keyboard layout of guest, e.g. us, de, fr, ru")
+                .takes_value(true))
+            .arg(Arg::new("delay")
+                .long("delay")
+                .about("Delay after each character in milliseconds")
+                .takes_value(true)))
+}
+
+pub fn run(socket: &str, args: &ArgMatches) -> io::Result<()> {
+    let (cmd, sub) = match args.subcommand() {
+        Some(cmd) => cmd,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no command given, see --help")),
+    };
+    let proxy = sub.value_of("proxy").unwrap_or_default();
+    let path = sub.value_of("path").unwrap_or_default();
+    let request = match cmd {
+        "status" | "sources" => json!({"command": "list"}),
+        "attach" => json!({"command": "add_source", "proxy": proxy, "path": path, "grab": sub.value_of("grab")}),
+        "detach" => json!({"command": "remove_source", "proxy": proxy, "path": path}),
+        "grab" => json!({"command": "grab", "proxy": proxy, "grab": true}),
+        "ungrab" => json!({"command": "grab", "proxy": proxy, "grab": false}),
+        "switch" => json!({"command": "switch", "proxy": proxy, "output": sub.value_of("output")}),
+        "reload" => json!({"command": "reload"}),
+        "inject" => inject_request(proxy, sub)?,
+        "type" => type_request(proxy, sub)?,
+        _ => unreachable!(),
+    };
+
+    let mut result = send(socket, &request)?;
+    if cmd == "sources" {
+        result = result.as_array().into_iter().flatten()
+            .find(|p| p["name"] == proxy)
+            .map(|p| p["sources"].clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no proxy device '{:}'", proxy)))?;
+    }
+
+    if args.is_present("json") {
+        println!("{:}", serde_json::to_string_pretty(&result)?);
+        return Ok(())
+    }
+    match cmd {
+        "status" => print_status(&result),
+        "sources" => print_table(&["PATH", "PAUSED"], result.as_array().into_iter().flatten()
+            .map(|s| vec![text(&s["path"]), yes_no(&s["paused"])])
+            .collect()),
+        "switch" => println!("Switched '{:}' to output {:}", proxy, result),
+        _ => {},
+    }
+    Ok(())
+}
+
+fn inject_request(proxy: &str, args: &ArgMatches) -> io::Result<Value> {
+    let invalid = |what: &str, arg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {:} '{:}'", what, arg));
+    let mut events = Vec::new();
+    for arg in args.values_of("event").into_iter().flatten() {
+        let parts: Vec<&str> = arg.split(':').collect();
+        match parts.as_slice() {
+            [type_, code, value] => {
+                let value: i32 = value.parse().map_err(|_| invalid("event", arg))?;
+                events.push(json!({"type": type_, "code": code, "value": value}));
+            },
+            _ => return Err(invalid("event", arg)),
+        }
+    }
+    let mut rel = serde_json::Map::new();
+    for arg in args.values_of("rel").into_iter().flatten() {
+        let (axis, value) = arg.split_once('=').ok_or_else(|| invalid("motion", arg))?;
+        let value: i32 = value.parse().map_err(|_| invalid("motion", arg))?;
+        rel.insert(axis.to_owned(), json!(value));
+    }
+
+    let mut request = json!({"command": "inject", "proxy": proxy});
+    if let Some(keys) = args.values_of("keys") {
+        request["keys"] = json!(keys.collect::<Vec<&str>>());
+    }
+    if !events.is_empty() {
+        request["events"] = json!(events);
+    }
+    if !rel.is_empty() {
+        request["rel"] = Value::Object(rel);
+    }
+    Ok(request)
+}
+
+fn type_request(proxy: &str, args: &ArgMatches) -> io::Result<Value> {
+    let text = match args.value_of("text") {
+        Some(text) => text.to_owned(),
+        None => {
+            // Single trailing newline comes from echo and alike, it's not typed
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            text.strip_suffix('\n').map(|t| t.to_owned()).unwrap_or(text)
+        },
+    };
+    let delay = match args.value_of("delay") {
+        Some(delay) => Some(delay.parse::<u64>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid delay '{:}'", delay)))?),
+        None => None,
+    };
+    Ok(json!({"command": "type", "proxy": proxy, "text": text, "layout": args.value_of("layout"), "delay": delay}))
+}
+
+/// Sends request to daemon and returns result of successful one
+fn send(socket: &str, request: &Value) -> io::Result<Value> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to connect to '{:}': {:}", socket, e)))?;
+    writeln!(stream, "{:}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let response: Value = serde_json::from_str(&line)?;
+    if response["ok"] == true {
+        Ok(response["result"].clone())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, text(&response["error"])))
+    }
+}
+
+fn print_status(proxies: &Value) {
+    let mut rows = Vec::new();
+    for p in proxies.as_array().into_iter().flatten() {
+        let outputs = p["outputs"].as_array().cloned().unwrap_or_default();
+        for (idx, out) in outputs.iter().enumerate() {
+            // Routers are listed with all their outputs, the active one is marked
+            let name = if outputs.len() == 1 {
+                text(&p["name"])
+            } else {
+                let mark = if p["active"] == idx { "*" } else { " " };
+                format!("{:}{:}/{:}", mark, text(&p["name"]), text(&out["name"]))
+            };
+            rows.push(vec![
+                name,
+                yes_no(&p["grabbed"]),
+                p["num_sources"].to_string(),
+                text(&out["dev_path"]),
+            ]);
+        }
+    }
+    print_table(&["NAME", "GRABBED", "SOURCES", "DEVICE"], rows);
+}
+
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let header: Vec<String> = header.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row.iter().zip(&widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        println!("{:}", line.join("  ").trim_end());
+    }
+}
+
+fn text(v: &Value) -> String {
+    v.as_str().map(|s| s.to_owned()).unwrap_or_else(|| v.to_string())
+}
+
+fn yes_no(v: &Value) -> String {
+    if v == true { "yes" } else { "no" }.to_owned()
+}
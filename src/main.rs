@@ -3,26 +3,504 @@
 extern crate pretty_env_logger;
 #[macro_use] extern crate serde_derive;
 
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use clap::Arg;
+use crossbeam::channel;
+use crossbeam::channel::{Receiver, Sender};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 
 mod udevdetect;
 mod proxydev;
 mod config;
+mod control;
+mod ctl;
 
 static DEFAULT_CONFIG_PATH: &str = "/etc/evdev-proxy/config.toml";
+static DEFAULT_PROFILE_DIR: &str = "/var/lib/evdev-proxy";
+static DEFAULT_CONTROL_SOCKET: &str = "/run/evdev-proxy/control.sock";
 
-fn selector_by_config(s: &config::DeviceSelector) -> Box<dyn udevdetect::DevFilter+Send+Sync> {
-    match s {
-        config::DeviceSelector::USBID{vendor, model} => {
+/// Write end of pipe that wakes up reload thread on SIGHUP
+static SIGHUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+fn selector_by_config(s: &config::DeviceSelector) -> io::Result<Box<dyn udevdetect::DevFilter+Send+Sync>> {
+    Ok(match s {
+        config::DeviceSelector::USBID{vendor, model, ..} => {
             Box::new(udevdetect::USBIDFilter::new(*vendor, *model))
         },
-        config::DeviceSelector::USBIDClass{vendor, model, class} => {
+        config::DeviceSelector::USBIDClass{vendor, model, class, ..} => {
             Box::new(udevdetect::USBIDClassFilter::new(*vendor, *model, *class))
+        },
+        config::DeviceSelector::Name{name, regex, ..} => {
+            Box::new(udevdetect::NameFilter::new(name, regex.unwrap_or(false))?)
+        },
+        config::DeviceSelector::Port{path, prefix, ..} => {
+            Box::new(udevdetect::PortFilter::new(path, prefix.unwrap_or(false)))
+        },
+        config::DeviceSelector::Serial{serial, vendor, model, ..} => {
+            Box::new(udevdetect::SerialFilter::new(serial, *vendor, *model))
+        },
+        config::DeviceSelector::InputID{bus, vendor, product, version, ..} => {
+            Box::new(udevdetect::InputIDFilter::new(*bus, *vendor, *product, *version))
+        },
+        config::DeviceSelector::All{selectors, ..} => {
+            Box::new(udevdetect::AllFilter::new(selectors.iter().map(selector_by_config).collect::<io::Result<_>>()?))
+        },
+        config::DeviceSelector::Any{selectors, ..} => {
+            Box::new(udevdetect::AnyFilter::new(selectors.iter().map(selector_by_config).collect::<io::Result<_>>()?))
+        },
+        config::DeviceSelector::Not{selector, ..} => {
+            Box::new(udevdetect::NotFilter::new(selector_by_config(selector)?))
+        },
+    })
+}
+
+/// Filters of selectors, each of them skips devices matched by any of `exclude`
+fn filters_by_config(selector: &Option<Vec<config::DeviceSelector>>, exclude: &Option<Vec<config::DeviceSelector>>) -> io::Result<Vec<Box<dyn udevdetect::DevFilter+Send+Sync>>> {
+    selector.iter().flatten().map(|s| {
+        let filter = selector_by_config(s)?;
+        let exclude = match exclude {
+            Some(exclude) if !exclude.is_empty() => exclude,
+            _ => return Ok(filter),
+        };
+        let excluded = udevdetect::AnyFilter::new(exclude.iter().map(selector_by_config).collect::<io::Result<_>>()?);
+        let filter: Box<dyn udevdetect::DevFilter+Send+Sync> = Box::new(udevdetect::AllFilter::new(vec![
+            filter,
+            Box::new(udevdetect::NotFilter::new(Box::new(excluded))),
+        ]));
+        Ok(filter)
+    }).collect()
+}
+
+/// Queries capabilities of matched device
+fn query_capabilities(event: &udevdetect::DevEvent) -> Option<proxydev::Capabilities> {
+    match proxydev::open_device(&event.devname)
+        .and_then(|dev| proxydev::Capabilities::from_device(&dev)) {
+        Ok(caps) => Some(caps),
+        Err(e) => {
+            error!("Failed to query capabilities of '{:?}': {:?}", event.devname, e);
+            None
+        },
+    }
+}
+
+fn save_profile(profile: &Path, caps: &proxydev::Capabilities) {
+    if let Err(e) = caps.save(profile) {
+        warn!("Failed to save capability profile '{:?}': {:?}", profile, e);
+    }
+}
+
+/// Change of running proxy, applied by its thread
+enum Update {
+    /// Replaces selectors and exclusions, sources matched by old ones only are removed
+    Selector(Option<Vec<config::DeviceSelector>>, Option<Vec<config::DeviceSelector>>),
+    /// Adds present devices that match selectors
+    Rescan,
+    /// Releases sources and destroys proxy device
+    Stop,
+}
+
+/// Running proxy device as defined in config
+struct Proxy {
+    device: config::Device,
+    updates: Sender<(Update, Sender<()>)>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Proxy {
+    /// Sends update to proxy thread and waits until it is applied
+    fn update(&self, update: Update) {
+        let (ack, done) = channel::bounded(1);
+        // Thread could already be finished after failure
+        if self.updates.send((update, ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    fn stop(self) {
+        info!("Stopping proxy device '{:?}'", self.device.name());
+        self.update(Update::Stop);
+        if self.thread.join().is_err() {
+            error!("Thread for device '{:?}' has panicked", self.device.name());
+        }
+    }
+}
+
+/// Proxy device thread state, adds devices matched by selectors to proxy.
+/// Proxy device of class without capabilities of its own and without cached
+/// profile is created once the first device is matched.
+struct ProxyThread {
+    device: config::Device,
+    name: String,
+    vendor: u16,
+    model: u16,
+    class: proxydev::SimpleDeviceClass,
+    // Capabilities given by class, none if they come from source device
+    caps: Option<proxydev::Capabilities>,
+    axes: Vec<proxydev::AbsAxis>,
+    selector: Option<Vec<config::DeviceSelector>>,
+    exclude: Option<Vec<config::DeviceSelector>>,
+    limits: proxydev::FrameLimits,
+    hotkeys: proxydev::Hotkeys,
+    outputs: Option<Vec<String>>,
+    profile: PathBuf,
+    // Capabilities of sources the proxy device was created from
+    profile_caps: Option<proxydev::Capabilities>,
+    registry: control::Registry,
+    pd: Option<Arc<proxydev::Simple>>,
+    // Sources added from udev events, as opposed to ones added through control socket
+    matched: HashMap<String, proxydev::GrabMode>,
+}
+
+impl ProxyThread {
+    fn new(dev: &config::Device, profile_dir: &Path, registry: &control::Registry) -> io::Result<ProxyThread> {
+        let mut hotkeys = proxydev::Hotkeys::default();
+        let (name, vendor, model, class, keys, axes, selector, exclude, frame_size, frame_timeout, grab_toggle, outputs) = match dev.clone() {
+            config::Device::Simple{name, vendor, model, class, keys, axes, selector, exclude, frame_size, frame_timeout, grab_toggle} =>
+                (name, vendor, model, class, keys, axes, selector, exclude, frame_size, frame_timeout, grab_toggle, None),
+            config::Device::Router{name, vendor, model, class, keys, axes, selector, exclude, frame_size, frame_timeout, grab_toggle, outputs, switch} => {
+                if outputs.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "router has no outputs"))
+                }
+                if switch.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "switch chord of router is empty"))
+                }
+                hotkeys.switch = proxydev::key_codes(&switch)?;
+                (name, vendor, model, class, keys, axes, selector, exclude, frame_size, frame_timeout, grab_toggle, Some(outputs))
+            },
+        };
+        if let Some(chord) = grab_toggle {
+            hotkeys.grab = proxydev::key_codes(&chord)?;
+        }
+        filters_by_config(&selector, &exclude)?;
+        let caps = class.capabilities(keys.unwrap_or_default())?;
+        let axes = axes.iter().flatten()
+            .map(proxydev::AbsAxisSpec::resolve)
+            .collect::<io::Result<_>>()?;
+        let mut limits = proxydev::FrameLimits::default();
+        if let Some(size) = frame_size {
+            limits.max_events = size;
+        }
+        if let Some(timeout) = frame_timeout {
+            limits.timeout = Duration::from_millis(timeout);
+        }
+
+        Ok(ProxyThread {
+            device: dev.clone(),
+            profile: profile_dir.join(format!("{:}.json", name)),
+            name, vendor, model, class, caps, axes, selector, exclude, limits, hotkeys, outputs,
+            profile_caps: None,
+            registry: registry.clone(),
+            pd: None,
+            matched: HashMap::new(),
+        })
+    }
+
+    fn spawn(self) -> Proxy {
+        let (updates, updates_rx) = channel::unbounded();
+        let device = self.device.clone();
+        let thread = thread::spawn(move || self.run(updates_rx));
+        Proxy{device, updates, thread}
+    }
+
+    fn run(mut self, updates: Receiver<(Update, Sender<()>)>) {
+        // create udev listener with device selectors
+        info!("Initializing udev listener for '{:?}'", self.name);
+        let mut dl = udevdetect::DevListener::new("input", 32);
+        // Selectors are validated before thread is spawned
+        for filter in filters_by_config(&self.selector, &self.exclude).expect("Invalid selector of proxy device") {
+            dl.add_filter(filter);
+        }
+        let dev_ev_listener = dl.listen()
+            .expect("Failed to listen to udev events");
+
+        // Thread finishes if device can't be created, so reload could start it again
+        let created = match self.caps.clone() {
+            Some(caps) => self.create(caps),
+            None => match proxydev::Capabilities::load(&self.profile) {
+                Ok(caps) => {
+                    info!("Using cached capability profile '{:?}' for '{:?}'", self.profile, self.name);
+                    self.profile_caps = Some(caps.clone());
+                    self.create(self.class.source_capabilities(caps))
+                },
+                Err(e) => {
+                    info!("No capability profile '{:?}' for '{:?}' ({:?}), waiting for source device", self.profile, self.name, e);
+                    Ok(())
+                },
+            },
+        };
+        if created.is_err() {
+            return
+        }
+
+        info!("Listening for udev events for '{:?}'", self.name);
+        loop {
+            channel::select! {
+                recv(dev_ev_listener) -> event => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    };
+                    debug!("Device event for {:?}: {:?}", self.name, event);
+                    match event.action {
+                        udevdetect::DevEventType::Add => {
+                            info!("Got matching device event for '{:?}': {:?} {:?}:{:?} ({:?}) at {:?}",
+                                  self.name, event.name, event.vendor, event.product, event.input_class, event.devpath);
+                            self.add(&event);
+                        },
+                        udevdetect::DevEventType::Remove => {
+                            // Removals of any devices are reported, only sources are of interest
+                            let is_source = self.pd.as_ref()
+                                .map_or(false, |pd| pd.sources().iter().any(|(path, _)| *path == event.devname));
+                            if is_source {
+                                info!("Got source device removal for '{:?}': {:?}", self.name, event.devname);
+                                self.remove(&event.devname);
+                            }
+                        },
+                        udevdetect::DevEventType::Unknown => {},
+                    }
+                    if let Some(pd) = &self.pd {
+                        info!("Number of devices: {:}", pd.num_sources());
+                    }
+                },
+                recv(updates) -> update => {
+                    let (update, ack) = match update {
+                        Ok(update) => update,
+                        Err(_) => break,
+                    };
+                    match update {
+                        Update::Selector(selector, exclude) => {
+                            info!("Updating selectors of '{:?}'", self.name);
+                            let present = dl.set_filters(filters_by_config(&selector, &exclude).expect("Invalid selector of proxy device"));
+                            self.selector = selector;
+                            self.exclude = exclude;
+                            let unmatched: Vec<String> = self.matched.keys()
+                                .filter(|path| !present.iter().any(|event| event.devname == **path))
+                                .cloned()
+                                .collect();
+                            for path in unmatched {
+                                info!("Device '{:?}' does not match selectors of '{:?}' anymore", path, self.name);
+                                self.remove(&path);
+                            }
+                            // Grab mode is applied when source is opened, so it's re-added
+                            for event in present {
+                                let grab = self.grab_mode(&event);
+                                if self.matched.get(&event.devname).map_or(false, |g| *g != grab) {
+                                    info!("Grab mode of '{:?}' for '{:?}' has changed to {:?}", event.devname, self.name, grab);
+                                    self.remove(&event.devname);
+                                    self.add(&event);
+                                }
+                            }
+                        },
+                        Update::Rescan => {
+                            for event in dl.scan() {
+                                if !self.matched.contains_key(&event.devname) {
+                                    self.add(&event);
+                                }
+                            }
+                        },
+                        Update::Stop => {
+                            self.shutdown();
+                            let _ = ack.send(());
+                            break
+                        },
+                    }
+                    let _ = ack.send(());
+                },
+            }
+        }
+        info!("Thread for device '{:?}' has finished", self.name);
+    }
+
+    /// Creates proxy device with capabilities of sources
+    fn create(&mut self, mut caps: proxydev::Capabilities) -> io::Result<()> {
+        caps.set_axes(&self.axes);
+
+        // create simple proxy device
+        let pd = match &self.outputs {
+            None => proxydev::Simple::new(self.name.as_str(), &caps, self.vendor, self.model, self.limits, &self.hotkeys),
+            Some(outputs) => proxydev::Simple::router(self.name.as_str(), outputs, &caps, self.vendor, self.model, self.limits, &self.hotkeys),
+        }.map(Arc::new);
+        let pd = match pd {
+            Ok(pd) => pd,
+            Err(e) => {
+                error!("Failed to create proxy device '{:?}': {:?}", self.name, e);
+                return Err(e)
+            },
+        };
+        self.registry.lock().unwrap().insert(self.name.to_owned(), pd.clone());
+        info!("Proxy device initialized as '{:?}'", pd.dev_path());
+        if self.outputs.is_some() {
+            for (output, path) in pd.outputs() {
+                info!("Router output '{:?}' initialized as '{:?}'", output, path);
+            }
+        }
+        self.pd = Some(pd);
+        Ok(())
+    }
+
+    fn add(&mut self, event: &udevdetect::DevEvent) {
+        // Virtual devices of proxies could match selectors of virtual bus
+        let is_proxy = self.registry.lock().unwrap().values()
+            .any(|pd| pd.outputs().iter().any(|(_, path)| *path == event.devname));
+        if is_proxy {
+            debug!("Skipping proxy device '{:?}' for '{:?}'", event.devname, self.name);
+            return
+        }
+        // Classes without capabilities of their own take them from sources
+        if self.caps.is_none() {
+            match (query_capabilities(event), self.profile_caps.as_mut()) {
+                (Some(caps), None) => {
+                    save_profile(&self.profile, &caps);
+                    // Device creation is retried with the next matched source
+                    if self.create(self.class.source_capabilities(caps.clone())).is_err() {
+                        return
+                    }
+                    self.profile_caps = Some(caps);
+                },
+                (Some(caps), Some(profile)) => {
+                    if profile.merge(&caps) {
+                        save_profile(&self.profile, profile);
+                        warn!("Device '{:?}' has capabilities missing in profile of '{:?}', restart is needed to use them",
+                              event.devname, self.name);
+                    }
+                },
+                (None, None) => return,
+                (None, Some(_)) => {},
+            }
+        }
+        let grab = self.grab_mode(event);
+        if let Some(pd) = &self.pd {
+            match pd.add_source_dev(&event.devname, grab) {
+                Ok(()) => {
+                    self.matched.insert(event.devname.to_owned(), grab);
+                },
+                Err(e) => error!("Failed to add matched device '{:?}': {:?}", event.devname, e),
+            }
+        }
+    }
+
+    /// Grab mode of the selector that matched device, event could be queued
+    /// before selectors were replaced, so index may be out of new ones
+    fn grab_mode(&self, event: &udevdetect::DevEvent) -> proxydev::GrabMode {
+        event.filter
+            .and_then(|idx| self.selector.as_ref().and_then(|s| s.get(idx)))
+            .map(|s| s.grab())
+            .unwrap_or_default()
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.matched.remove(path);
+        if let Some(pd) = &self.pd {
+            // Source could already be dropped by event loop after failed read
+            if let Err(e) = pd.remove_source_dev(path) {
+                debug!("Failed to remove matched device '{:?}': {:?}", path, e);
+            }
+        }
+    }
+
+    /// Releases sources right away, so other proxies could grab them, and
+    /// destroys proxy device
+    fn shutdown(&mut self) {
+        if let Some(pd) = self.pd.take() {
+            for (path, _) in pd.sources() {
+                self.remove(&path);
+            }
+            self.registry.lock().unwrap().remove(&self.name);
+        }
+    }
+}
+
+/// Re-reads config and applies changes of devices: changed proxies are
+/// re-created, unchanged ones get new selectors in place
+fn reload(config_path: &str, proxies: &mut Vec<Proxy>, control: &Option<config::ControlConfig>, registry: &control::Registry) -> io::Result<()> {
+    info!("Reloading config");
+    let conf = config::read_config(config_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if conf.control != *control {
+        warn!("Changes of control socket config are applied after restart only");
+    }
+    let profile_dir = PathBuf::from(conf.profile_dir.as_deref().unwrap_or(DEFAULT_PROFILE_DIR));
+
+    let mut stopped = std::mem::take(proxies);
+    let mut kept = Vec::new();
+    let mut created = Vec::new();
+    // Everything is validated before any proxy is touched
+    let res = conf.device.into_iter().try_for_each(|dev| {
+        let invalid = |e: io::Error| io::Error::new(e.kind(), format!("invalid device '{:}': {:}", dev.name(), e));
+        // Proxies that have failed are started again
+        match stopped.iter().position(|p| !p.thread.is_finished() && p.device.same_proxy(&dev)) {
+            Some(idx) => {
+                filters_by_config(dev.selector(), dev.exclude()).map_err(invalid)?;
+                kept.push((stopped.remove(idx), dev));
+            },
+            None => created.push(ProxyThread::new(&dev, &profile_dir, registry).map_err(invalid)?),
         }
+        Ok(())
+    });
+    if let Err(e) = res {
+        proxies.extend(stopped);
+        proxies.extend(kept.into_iter().map(|(p, _)| p));
+        return Err(e)
     }
+
+    // Sources are released first, so other proxies could pick them up
+    for p in stopped {
+        p.stop();
+    }
+    let mut rescan = Vec::new();
+    for (mut p, dev) in kept {
+        if p.device.selector() != dev.selector() || p.device.exclude() != dev.exclude() {
+            p.update(Update::Selector(dev.selector().clone(), dev.exclude().clone()));
+            rescan.push(p.device.name().to_owned());
+        }
+        p.device = dev;
+        proxies.push(p);
+    }
+    for p in proxies.iter().filter(|p| rescan.iter().any(|n| n == p.device.name())) {
+        p.update(Update::Rescan);
+    }
+    for pt in created {
+        info!("Starting proxy device '{:?}'", pt.name);
+        proxies.push(pt.spawn());
+    }
+    info!("Config reloaded, {:} proxy device(s) running", proxies.len());
+    Ok(())
+}
+
+extern "C" fn on_sighup(_: nix::libc::c_int) {
+    let _ = nix::unistd::write(SIGHUP_PIPE.load(Ordering::SeqCst), &[1]);
+}
+
+/// Turns SIGHUP into reload requests
+fn watch_sighup(reload: Sender<control::ReloadRequest>) -> io::Result<()> {
+    let (rx, tx) = nix::unistd::pipe()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    SIGHUP_PIPE.store(tx, Ordering::SeqCst);
+    // Blocking reads of other threads are restarted after handler
+    let action = SigAction::new(SigHandler::Handler(on_sighup), SaFlags::SA_RESTART, SigSet::empty());
+    unsafe { sigaction(Signal::SIGHUP, &action) }
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    let mut rx = unsafe { File::from_raw_fd(rx) };
+    thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        while let Ok(1) = rx.read(&mut buf) {
+            info!("Got SIGHUP");
+            reload.send(None).unwrap();
+        }
+    });
+    Ok(())
 }
 
 fn main() {
@@ -33,68 +511,70 @@ fn main() {
             .short('c')
             .long("config")
             .about("Path to config file")
-            .takes_value(true)).get_matches();
+            .takes_value(true))
+        .subcommand(ctl::subcommand())
+        .get_matches();
 
     let config_path = app.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH);
+
+    if let Some(("ctl", args)) = app.subcommand() {
+        // Config is needed only to find control socket
+        let socket = match args.value_of("socket") {
+            Some(socket) => socket.to_owned(),
+            None => config::read_config(config_path).ok()
+                .and_then(|c| c.control)
+                .map(|c| c.path)
+                .unwrap_or_else(|| DEFAULT_CONTROL_SOCKET.to_owned()),
+        };
+        if let Err(e) = ctl::run(&socket, args) {
+            eprintln!("Error: {:}", e);
+            std::process::exit(1);
+        }
+        return
+    }
     let conf = config::read_config(config_path)
         .expect("Failed to read config file");
 
-    match env::var("RUST_LOG") {
-        Err(_) => {
-            info!("RUST_LOG not set, using log-level from config");
-            env::set_var("RUST_LOG", &conf.log_level);
-        },
-        Ok(_) => (),
+    if env::var("RUST_LOG").is_err() {
+        info!("RUST_LOG not set, using log-level from config");
+        env::set_var("RUST_LOG", &conf.log_level);
     }
     pretty_env_logger::init();
     info!("Parsed config: {:#?}", conf);
 
-    let mut threads = Vec::new();
-    for dev in conf.device {
-        match dev {
-            config::Device::Simple{name, vendor, model, class, selector} => {
-                let t = thread::spawn(move || {
-                    // create simple proxy device
-                    let pd = proxydev::Simple::new(name.as_str(), class, vendor, model, )
-                        .expect("Failed to create proxy device");
-                    info!("Proxy device initialized as '{:?}'", pd.dev_path());
-
-                    // create udev listener with device selectors
-                    info!("Initializing udev listener for '{:?}'", name);
-                    let mut dl = udevdetect::DevListener::new("input", 32);
-                    for s in selector.as_ref().unwrap_or(&Vec::new()) {
-                        let filter = selector_by_config(s);
-                        dl.add_filter(filter);
-                    }
-                    let dev_ev_listener = dl.listen()
-                        .expect("Failed to listen to udev events");
-
-                    info!("Listening for udev events for '{:?}'", name);
-                    for event in dev_ev_listener.iter() {
-                        info!("Device event for {:?}: {:?}", name, event);
-                        if event.action == udevdetect::DevEventType::Add {
-                            info!("Got matching device event for '{:?}': {:?}:{:?} ({:?})",
-                                  name, event.vendor, event.product, event.input_class);
-
-                            match pd.add_source_dev(&event.devname) {
-                                Err(e) => {
-                                    error!("Failed to add matched device '{:?}': {:?}", event.devname, e);
-                                },
-                                Ok(_) => {},
-                            };
-                        }
-                        info!("Number of devices: {:}", pd.num_sources());
-                    }
-                    info!("Thread for device '{:?}' has finished", name);
-                });
-                threads.push(t);
-            }
+    let profile_dir = PathBuf::from(conf.profile_dir.as_deref().unwrap_or(DEFAULT_PROFILE_DIR));
+
+    let (reload_tx, reload_rx) = channel::unbounded();
+    watch_sighup(reload_tx.clone())
+        .expect("Failed to set SIGHUP handler");
+
+    let registry: control::Registry = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(ctl) = &conf.control {
+        let ctx = control::Context {
+            registry: registry.clone(),
+            layout_dir: ctl.layout_dir.clone(),
+            reload: reload_tx,
+        };
+        if let Err(e) = control::listen(&ctl.path, ctl.mode, ctx) {
+            error!("Failed to create control socket '{:?}': {:?}", ctl.path, e);
         }
     }
 
-    // Wait for all threads
-    for t in threads {
-        t.join().unwrap();
+    let mut proxies = Vec::new();
+    for dev in &conf.device {
+        let pt = ProxyThread::new(dev, &profile_dir, &registry)
+            .expect("Invalid proxy device");
+        proxies.push(pt.spawn());
     }
-}
 
+    // Serve reload requests until the end
+    for request in reload_rx.iter() {
+        let res = reload(config_path, &mut proxies, &conf.control, &registry);
+        if let Err(e) = &res {
+            error!("Failed to reload config: {:}", e);
+        }
+        if let Some(reply) = request {
+            let _ = reply.send(res);
+        }
+    }
+}
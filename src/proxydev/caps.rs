@@ -0,0 +1,352 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use input_linux::{AbsoluteAxis, AbsoluteInfo, AbsoluteInfoSetup, EventKind, EvdevHandle};
+use input_linux::sys;
+
+use crate::proxydev::codes;
+use crate::proxydev::mt::MAX_SLOT;
+
+/// Absolute axis with its `input_absinfo` parameters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AbsAxis {
+    pub axis: u16,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+impl AbsAxis {
+    pub fn setup(&self) -> io::Result<AbsoluteInfoSetup> {
+        let axis = AbsoluteAxis::from_code(self.axis)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("invalid abs axis {:}", self.axis)))?;
+        Ok(AbsoluteInfoSetup {
+            axis,
+            info: AbsoluteInfo {
+                value: 0,
+                minimum: self.minimum,
+                maximum: self.maximum,
+                fuzz: self.fuzz,
+                flat: self.flat,
+                resolution: self.resolution,
+            },
+        })
+    }
+}
+
+/// Set of event codes a virtual device advertises, all codes are raw kernel values.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub events: Vec<u16>,
+    pub keys: Vec<u16>,
+    pub relative: Vec<u16>,
+    pub absolute: Vec<AbsAxis>,
+    pub misc: Vec<u16>,
+    pub leds: Vec<u16>,
+    pub properties: Vec<u16>,
+    #[serde(default)]
+    pub ff: Vec<u16>,
+    #[serde(default)]
+    pub ff_effects: u32,
+}
+
+/// Event types copied from source devices, autorepeat is left out as kernel
+/// would repeat keys of virtual device on its own
+static CLONED_EVENTS: [EventKind; 7] = [
+    EventKind::Synchronize,
+    EventKind::Key,
+    EventKind::Relative,
+    EventKind::Absolute,
+    EventKind::Misc,
+    EventKind::Led,
+    EventKind::ForceFeedback,
+];
+
+/// Default number of force-feedback effects for `CapabilitySpec`
+static DEFAULT_FF_EFFECTS: u32 = 16;
+
+/// Number of force-feedback codes, `FF_CNT` from linux/input.h
+const FF_CNT: u16 = 0x80;
+
+impl Capabilities {
+    /// Queries capabilities of the source device with EVIOCGBIT/EVIOCGABS/EVIOCGPROP
+    pub fn from_device(dev: &EvdevHandle<File>) -> io::Result<Capabilities> {
+        let events = dev.event_bits()?;
+        let mut caps = Capabilities::default();
+
+        for e in CLONED_EVENTS.iter().filter(|e| events.get(**e)) {
+            caps.events.push(*e as u16);
+        }
+        if events.get(EventKind::Key) {
+            caps.keys = dev.key_bits()?.iter().map(|k| k as u16).collect();
+        }
+        if events.get(EventKind::Relative) {
+            caps.relative = dev.relative_bits()?.iter().map(|r| r as u16).collect();
+        }
+        if events.get(EventKind::Absolute) {
+            for a in dev.absolute_bits()?.iter() {
+                let info = dev.absolute_info(a)?;
+                caps.absolute.push(AbsAxis {
+                    axis: a as u16,
+                    minimum: info.minimum,
+                    maximum: info.maximum,
+                    fuzz: info.fuzz,
+                    flat: info.flat,
+                    resolution: info.resolution,
+                });
+            }
+        }
+        if events.get(EventKind::Misc) {
+            caps.misc = dev.misc_bits()?.iter().map(|m| m as u16).collect();
+        }
+        if events.get(EventKind::Led) {
+            caps.leds = dev.led_bits()?.iter().map(|l| l as u16).collect();
+        }
+        if events.get(EventKind::ForceFeedback) {
+            let mut bits = [0u8; FF_CNT as usize / 8];
+            dev.event_bits_raw(EventKind::ForceFeedback, &mut bits)?;
+            caps.ff = (0..FF_CNT)
+                .filter(|f| bits[*f as usize / 8] & (1 << (f % 8)) != 0)
+                .collect();
+            caps.ff_effects = dev.effects_count()? as u32;
+        }
+        caps.properties = dev.device_properties()?.iter().map(|p| p as u16).collect();
+
+        Ok(caps)
+    }
+
+    /// Adds codes and axes of `other` that are missing, returns whether
+    /// anything was added
+    pub fn merge(&mut self, other: &Capabilities) -> bool {
+        let mut changed = false;
+        let codes = [
+            (&mut self.events, &other.events),
+            (&mut self.keys, &other.keys),
+            (&mut self.relative, &other.relative),
+            (&mut self.misc, &other.misc),
+            (&mut self.leds, &other.leds),
+            (&mut self.properties, &other.properties),
+            (&mut self.ff, &other.ff),
+        ];
+        for (own, new) in codes {
+            for c in new {
+                if !own.contains(c) {
+                    own.push(*c);
+                    changed = true;
+                }
+            }
+        }
+        for a in &other.absolute {
+            if !self.absolute.iter().any(|own| own.axis == a.axis) {
+                self.absolute.push(*a);
+                changed = true;
+            }
+        }
+        if other.ff_effects > self.ff_effects {
+            self.ff_effects = other.ff_effects;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Replaces absinfo of listed axes, axes that device doesn't have are added
+    pub fn set_axes(&mut self, axes: &[AbsAxis]) {
+        for axis in axes {
+            match self.absolute.iter_mut().find(|a| a.axis == axis.axis) {
+                Some(a) => *a = *axis,
+                None => self.absolute.push(*axis),
+            }
+        }
+        if !self.absolute.is_empty() && !self.events.contains(&(sys::EV_ABS as u16)) {
+            self.events.push(sys::EV_ABS as u16);
+        }
+    }
+
+    pub fn load<P: AsRef<Path> + Debug>(path: P) -> io::Result<Capabilities> {
+        debug!("Loading capability profile from '{:?}'", path);
+        let f = File::open(path)?;
+        serde_json::from_reader(f)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path> + Debug>(&self, path: P) -> io::Result<()> {
+        info!("Saving capability profile to '{:?}'", path);
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(f, self)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Absolute axis in `CapabilitySpec`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AbsAxisSpec {
+    pub axis: String,
+    pub min: i32,
+    pub max: i32,
+    pub fuzz: Option<i32>,
+    pub flat: Option<i32>,
+    pub resolution: Option<i32>,
+}
+
+/// Capabilities given by names from linux/input-event-codes.h, e.g. `KEY_VOLUMEUP`
+/// or `BTN_SIDE`. Keys also accept inclusive ranges like `KEY_F13..KEY_F24`.
+/// Event types are derived from the listed codes, `events` is needed only
+/// for types without codes of their own.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CapabilitySpec {
+    pub events: Option<Vec<String>>,
+    pub keys: Option<Vec<String>>,
+    pub relative: Option<Vec<String>>,
+    pub absolute: Option<Vec<AbsAxisSpec>>,
+    pub misc: Option<Vec<String>>,
+    pub leds: Option<Vec<String>>,
+    pub properties: Option<Vec<String>>,
+    pub ff: Option<Vec<String>>,
+    pub ff_effects: Option<u32>,
+}
+
+fn lookup(table: &[(&str, u16)], kind: &str, name: &str) -> io::Result<u16> {
+    codes::code_by_name(table, name)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("unknown {:} '{:}'", kind, name)))
+}
+
+fn lookup_all(table: &[(&str, u16)], kind: &str, names: &Option<Vec<String>>) -> io::Result<Vec<u16>> {
+    let mut res: Vec<u16> = Vec::new();
+    for name in names.iter().flatten() {
+        let (first, last) = match name.find("..") {
+            Some(idx) => (lookup(table, kind, &name[..idx])?, lookup(table, kind, &name[idx+2..])?),
+            None => {
+                let code = lookup(table, kind, name)?;
+                (code, code)
+            },
+        };
+        if first > last {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("empty {:} range '{:}'", kind, name)))
+        }
+        res.extend((first..=last).filter(|c| !res.contains(c)).collect::<Vec<u16>>());
+    }
+    Ok(res)
+}
+
+/// Key codes by their names, e.g. `KEY_LEFTCTRL`
+pub fn key_codes(names: &[String]) -> io::Result<Vec<u16>> {
+    lookup_all(&codes::KEYS, "key", &Some(names.to_vec()))
+}
+
+impl AbsAxisSpec {
+    pub fn resolve(&self) -> io::Result<AbsAxis> {
+        let axis = lookup(&codes::ABSOLUTE, "absolute axis", &self.axis)?;
+        if axis == sys::ABS_MT_SLOT as u16 && !(0..=MAX_SLOT).contains(&self.max) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("ABS_MT_SLOT maximum {:} is out of 0..={:}", self.max, MAX_SLOT)))
+        }
+        Ok(AbsAxis {
+            axis,
+            minimum: self.min,
+            maximum: self.max,
+            fuzz: self.fuzz.unwrap_or(0),
+            flat: self.flat.unwrap_or(0),
+            resolution: self.resolution.unwrap_or(0),
+        })
+    }
+}
+
+impl CapabilitySpec {
+    pub fn resolve(&self) -> io::Result<Capabilities> {
+        let mut caps = Capabilities {
+            events: lookup_all(&codes::EVENTS, "event type", &self.events)?,
+            keys: lookup_all(&codes::KEYS, "key", &self.keys)?,
+            relative: lookup_all(&codes::RELATIVE, "relative axis", &self.relative)?,
+            absolute: Vec::new(),
+            misc: lookup_all(&codes::MISC, "misc event", &self.misc)?,
+            leds: lookup_all(&codes::LEDS, "led", &self.leds)?,
+            properties: lookup_all(&codes::PROPERTIES, "property", &self.properties)?,
+            ff: lookup_all(&codes::FORCE_FEEDBACK, "force-feedback", &self.ff)?,
+            ff_effects: 0,
+        };
+        if !caps.ff.is_empty() {
+            caps.ff_effects = self.ff_effects.unwrap_or(DEFAULT_FF_EFFECTS);
+        }
+        for a in self.absolute.iter().flatten() {
+            caps.absolute.push(a.resolve()?);
+        }
+
+        let derived = [
+            (sys::EV_SYN, true),
+            (sys::EV_KEY, !caps.keys.is_empty()),
+            (sys::EV_REL, !caps.relative.is_empty()),
+            (sys::EV_ABS, !caps.absolute.is_empty()),
+            (sys::EV_MSC, !caps.misc.is_empty()),
+            (sys::EV_LED, !caps.leds.is_empty()),
+            (sys::EV_FF, !caps.ff.is_empty()),
+        ];
+        for (ev, _) in derived.iter().filter(|(_, used)| *used) {
+            if !caps.events.contains(&(*ev as u16)) {
+                caps.events.push(*ev as u16);
+            }
+        }
+        Ok(caps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Option<Vec<String>> {
+        Some(list.iter().map(|n| n.to_string()).collect())
+    }
+
+    fn slot_axis(max: i32) -> AbsAxisSpec {
+        AbsAxisSpec { axis: "ABS_MT_SLOT".to_owned(), min: 0, max, fuzz: None, flat: None, resolution: None }
+    }
+
+    #[test]
+    fn ranges_are_inclusive() {
+        let codes = lookup_all(&codes::KEYS, "key", &names(&["KEY_F13..KEY_F24"])).unwrap();
+        let expected: Vec<u16> = (sys::KEY_F13 as u16..=sys::KEY_F24 as u16).collect();
+        assert_eq!(codes, expected);
+        assert_eq!(codes.len(), 12);
+    }
+
+    #[test]
+    fn single_element_range() {
+        let codes = lookup_all(&codes::KEYS, "key", &names(&["KEY_A..KEY_A"])).unwrap();
+        assert_eq!(codes, vec![sys::KEY_A as u16]);
+    }
+
+    #[test]
+    fn empty_and_unknown_ranges_are_rejected() {
+        for name in &["KEY_F24..KEY_F13", "KEY_F13..", "..KEY_F24", "KEY_F13..KEY_NOPE"] {
+            let err = lookup_all(&codes::KEYS, "key", &names(&[name])).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", name);
+        }
+    }
+
+    #[test]
+    fn duplicates_are_removed_in_order() {
+        let list = names(&["KEY_2", "KEY_1..KEY_3", "KEY_2", "KEY_1"]);
+        let codes = lookup_all(&codes::KEYS, "key", &list).unwrap();
+        assert_eq!(codes, vec![sys::KEY_2 as u16, sys::KEY_1 as u16, sys::KEY_3 as u16]);
+    }
+
+    #[test]
+    fn missing_list_is_empty() {
+        assert_eq!(lookup_all(&codes::KEYS, "key", &None).unwrap(), Vec::<u16>::new());
+    }
+
+    #[test]
+    fn slot_maximum_is_limited() {
+        assert_eq!(slot_axis(9).resolve().unwrap().maximum, 9);
+        assert_eq!(slot_axis(MAX_SLOT).resolve().unwrap().maximum, MAX_SLOT);
+        assert!(slot_axis(-1).resolve().is_err());
+        assert!(slot_axis(MAX_SLOT + 1).resolve().is_err());
+    }
+}
@@ -1,82 +1,308 @@
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::{Receiver, Sender, TrySendError};
 use crossbeam::channel;
-use input_linux::sys::input_event;
+use input_linux::sys;
+use input_linux::UInputHandle;
+use input_linux::sys::{ff_effect, input_event};
 
-use crate::proxydev::evdev::device_poller;
-use crate::proxydev::uinput::{new_uinput_aio, new_uinput_kbd, new_uinput_mouse};
+use crate::proxydev::evdev::{device_poller, GrabMode, SourceDev};
+use crate::proxydev::event::{is_syn_dropped, is_syn_report, new_event, syn_report};
+use crate::proxydev::caps::{Capabilities, CapabilitySpec};
+use crate::proxydev::inject;
+use crate::proxydev::layout::Layout;
+use crate::proxydev::mt::{SlotMap, MAX_SLOT};
+use crate::proxydev::uinput::{aio_caps, gamepad_caps, kbd_caps, mouse_caps, new_uinput, tablet_caps, touchpad_caps};
+
+type SourceList = Arc<Mutex<Vec<(String, SourceDev)>>>;
+
+/// Name under which injected keys are held, it's never a path of source
+const INJECTED: &str = "<injected>";
 
 #[derive(Debug)]
 pub struct Simple {
     name: String,
-    sources: Arc<Mutex<Vec<(String, Receiver<input_event>)>>>,
+    sources: SourceList,
     ch_reload: (Sender<bool>, Receiver<bool>),
+    ch_command: (Sender<Command>, Receiver<Command>),
+    outputs: Vec<Output>,
+    // Index of output that receives events
+    active: Arc<AtomicUsize>,
+    // Whether sources are grabbed, they're handed back to host otherwise
+    grabbed: Arc<AtomicBool>,
+    caps: Capabilities,
+}
+
+/// Requests to event loop
+#[derive(Debug)]
+enum Command {
+    Grab(bool),
+    Switch(usize),
+    Inject(Vec<input_event>),
+    /// Destroys virtual devices and stops event loop
+    Shutdown,
+}
+
+/// Key chords handled by proxy itself, empty chord is disabled
+#[derive(Debug, Clone, Default)]
+pub struct Hotkeys {
+    /// Switches router to the next output
+    pub switch: Vec<u16>,
+    /// Hands sources back to host or grabs them again
+    pub grab: Vec<u16>,
+}
+
+/// Virtual device of proxy
+#[derive(Debug)]
+struct Output {
+    name: String,
     devpath: String,
+    // LED state set by consumer of virtual device, code -> value
+    leds: Arc<Mutex<HashMap<u16, i32>>>,
+    // Force-feedback effects uploaded by consumer of virtual device
+    effects: Arc<Mutex<Effects>>,
+}
+
+/// Force-feedback effects by their id on virtual device
+#[derive(Default)]
+struct Effects(HashMap<i16, ff_effect>);
+
+impl Debug for Effects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SimpleDeviceClass {
     Keyboard,
     Mouse,
     AIO,
+    /// Gamepad with standard buttons, sticks, triggers, d-pad and rumble
+    Gamepad,
+    /// Pen tablet, axes and their ranges are copied from the matched source device
+    Tablet,
+    /// Multitouch touchpad, contacts of all sources share its slots
+    Touchpad,
+    /// Copies capabilities of the matched source device
+    Clone,
+    /// Capabilities listed explicitly in config
+    Custom(CapabilitySpec),
+}
+
+/// Set of keys offered by `Keyboard` and `AIO` classes
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum KeyProfile {
+    /// Standard 104/105-key keyboard
+    #[default]
+    Basic,
+    /// All keyboard keys, including multimedia, consumer control and
+    /// international ones
+    Full,
+}
+
+impl SimpleDeviceClass {
+    /// Capabilities of the virtual device, `None` if they depend on source device
+    pub fn capabilities(&self, keys: KeyProfile) -> io::Result<Option<Capabilities>> {
+        match self {
+            SimpleDeviceClass::Keyboard => Ok(Some(kbd_caps(keys))),
+            SimpleDeviceClass::Mouse => Ok(Some(mouse_caps())),
+            SimpleDeviceClass::AIO => Ok(Some(aio_caps(keys))),
+            SimpleDeviceClass::Gamepad => Ok(Some(gamepad_caps())),
+            SimpleDeviceClass::Tablet => Ok(None),
+            SimpleDeviceClass::Touchpad => Ok(None),
+            SimpleDeviceClass::Clone => Ok(None),
+            SimpleDeviceClass::Custom(spec) => spec.resolve().map(Some),
+        }
+    }
+
+    /// Capabilities of the virtual device made from capabilities of the source device
+    pub fn source_capabilities(&self, source: Capabilities) -> Capabilities {
+        match self {
+            SimpleDeviceClass::Tablet => tablet_caps(&source),
+            SimpleDeviceClass::Touchpad => touchpad_caps(&source),
+            _ => source,
+        }
+    }
+}
+
+/// Limits for events buffered from a single source until its `SYN_REPORT`.
+///
+/// Frame that grows over `max_events` or stays incomplete longer than
+/// `timeout` is written as is, followed by a synthetic `SYN_REPORT`.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    pub max_events: usize,
+    pub timeout: Duration,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_events: 64,
+            timeout: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Source as seen by event loop until the next reload
+struct LocalSource {
+    name: String,
+    events: Receiver<input_event>,
+    abs_ranges: HashMap<u16, (i32, i32)>,
+    paused: bool,
+}
+
+/// Events of a single source, collected until its `SYN_REPORT`
+struct Frame {
+    events: Vec<input_event>,
+    started: Instant,
 }
 
 impl Simple {
-    pub fn new(name: &str, class: SimpleDeviceClass, vendor: u16, model: u16) -> io::Result<Simple> {
-        info!("Creating new simple proxy device '{:?}' ({:04x}:{:04x})", name, vendor, model);
-        let uin = match class {
-            SimpleDeviceClass::Keyboard => {
-                new_uinput_kbd(name, vendor, model)?
-            },
-            SimpleDeviceClass::Mouse => {
-                new_uinput_mouse(name, vendor, model)?
-            },
-            SimpleDeviceClass::AIO => {
-                new_uinput_aio(name, vendor, model)?
+    pub fn new(name: &str, caps: &Capabilities, vendor: u16, model: u16, limits: FrameLimits, hotkeys: &Hotkeys) -> io::Result<Simple> {
+        Simple::router(name, &[name.to_owned()], caps, vendor, model, limits, hotkeys)
+    }
+
+    /// Proxy with several virtual devices, events of sources go to one of them
+    /// at a time and pressing `switch` hotkey passes them to the next one
+    pub fn router(name: &str, outputs: &[String], caps: &Capabilities, vendor: u16, model: u16, limits: FrameLimits, hotkeys: &Hotkeys) -> io::Result<Simple> {
+        // Multitouch contacts of all sources are spread over slots of virtual device
+        let slot_map = match caps.absolute.iter().find(|a| a.axis == sys::ABS_MT_SLOT as u16) {
+            Some(a) if !(0..=MAX_SLOT).contains(&a.maximum) => {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("ABS_MT_SLOT maximum {:} is out of 0..={:}", a.maximum, MAX_SLOT)))
             },
+            Some(a) => Some(SlotMap::new(a.maximum as usize + 1)),
+            None => None,
         };
-
-        let dev = Simple {
+        let mut dev = Simple {
             name: name.to_owned(),
             sources: Arc::new(Mutex::new(Vec::new())),
             ch_reload: channel::bounded(1),
-            devpath: uin.evdev_path().unwrap().into_os_string().into_string().unwrap(),
+            ch_command: channel::unbounded(),
+            outputs: Vec::with_capacity(outputs.len()),
+            active: Arc::new(AtomicUsize::new(0)),
+            grabbed: Arc::new(AtomicBool::new(true)),
+            caps: caps.clone(),
         };
 
+        let mut uins = Vec::with_capacity(outputs.len());
+        for (idx, out_name) in outputs.iter().enumerate() {
+            info!("Creating new simple proxy device '{:?}' ({:04x}:{:04x})", out_name, vendor, model);
+            let uin = Arc::new(new_uinput(out_name, vendor, model, caps)?);
+            let output = Output {
+                name: out_name.to_owned(),
+                devpath: uin.evdev_path().unwrap().into_os_string().into_string().unwrap(),
+                leds: Arc::new(Mutex::new(HashMap::new())),
+                effects: Arc::new(Mutex::new(Effects::default())),
+            };
+
+            {
+                let uin = uin.clone();
+                let sources = dev.sources.clone();
+                let leds = output.leds.clone();
+                let effects = output.effects.clone();
+                let active = dev.active.clone();
+                let dev_name = out_name.to_owned();
+                thread::spawn(move || uinput_reader(&uin, &dev_name, idx, &active, &sources, &leds, &effects));
+            }
+            uins.push(uin);
+            dev.outputs.push(output);
+        }
+
         // Those vars if for thread
         let sources = dev.sources.clone();
+        let abs_ranges: HashMap<u16, (i32, i32)> = caps.absolute.iter()
+            .map(|a| (a.axis, (a.minimum, a.maximum)))
+            .collect();
+        let mut router = Router {
+            dev_name: name.to_owned(),
+            outputs: uins,
+            leds: dev.outputs.iter().map(|o| o.leds.clone()).collect(),
+            effects: dev.outputs.iter().map(|o| o.effects.clone()).collect(),
+            active: dev.active.clone(),
+            grabbed: dev.grabbed.clone(),
+            pending_grab: false,
+            hotkeys: hotkeys.clone(),
+            sources: dev.sources.clone(),
+            pressed: HashMap::new(),
+            held_keys: HashMap::new(),
+            slot_map,
+        };
         let ch_reload = dev.ch_reload.1.clone();
+        let ch_command = dev.ch_command.1.clone();
         let dev_name = name.to_owned();
         thread::spawn(move || {
             info!("Starting event loop for proxy device '{:}'", dev_name);
+            // Incomplete frames are kept across reloads, so adding a source
+            // does not break frames of other sources
+            let mut frames: HashMap<String, Frame> = HashMap::new();
+
             'device: loop { // Initialization loop
                 let mut event_selector = channel::Select::new();
                 let mut local_sources;
 
                 // Copy all sources to thread-local vector
                 {
-                    let psrc = &sources.lock().unwrap();
+                    let psrc = sources.lock().unwrap();
                     local_sources = Vec::with_capacity(psrc.len());
                     for (name, s) in psrc.iter() {
-                        local_sources.push((name.to_owned(), s.clone()));
+                        local_sources.push(LocalSource {
+                            name: name.to_owned(),
+                            events: s.events().clone(),
+                            abs_ranges: s.abs_ranges().clone(),
+                            paused: s.is_paused(),
+                        });
                     }
                 }
+                // Paused sources are treated as removed ones
+                let active = |n: &str| local_sources.iter().any(|s| s.name == n && !s.paused);
+                frames.retain(|n, _| active(n));
+                router.release_gone(active);
 
-                // Populate event selector with all sources and reload signal channel
-                for (_, src) in &local_sources {
-                    event_selector.recv(&src);
+                // Populate event selector with all sources, reload signal and command channels
+                for src in &local_sources {
+                    event_selector.recv(&src.events);
                 }
                 event_selector.recv(&ch_reload);
+                event_selector.recv(&ch_command);
 
                 // Source/reload event loop
                 loop {
-                    let op = event_selector.select();
+                    // Wake up in time to flush the oldest incomplete frame
+                    let deadline = frames.values()
+                        .map(|f| f.started + limits.timeout)
+                        .min();
+                    let op = match deadline {
+                        None => event_selector.select(),
+                        Some(d) => match event_selector.select_deadline(d) {
+                            Ok(op) => op,
+                            Err(_) => {
+                                let now = Instant::now();
+                                let expired: Vec<String> = frames.iter()
+                                    .filter(|(_, f)| f.started + limits.timeout <= now)
+                                    .map(|(n, _)| n.to_owned())
+                                    .collect();
+                                for n in expired {
+                                    warn!("Source '{:?}' did not finish frame in {:?}, flushing", n, limits.timeout);
+                                    let mut frame = frames.remove(&n).unwrap();
+                                    frame.events.push(syn_report());
+                                    router.write_source_frame(&n, &frame.events);
+                                }
+                                continue
+                            },
+                        },
+                    };
                     let op_idx = op.index();
 
                     if op_idx == local_sources.len() {
@@ -85,54 +311,617 @@ impl Simple {
                         op.recv(&ch_reload).unwrap();
                         continue 'device;
                     }
+                    if op_idx == local_sources.len() + 1 {
+                        match op.recv(&ch_command).unwrap() {
+                            Command::Grab(grab) => router.set_grab(grab),
+                            Command::Switch(output) => router.switch(output),
+                            Command::Inject(events) => router.inject(&events),
+                            Command::Shutdown => break 'device,
+                        }
+                        continue
+                    }
 
-                    let (n, rx) = &local_sources[op_idx];
-                    let ev = match op.recv(rx) {
+                    let src = &local_sources[op_idx];
+                    let n = &src.name;
+                    let mut ev = match op.recv(&src.events) {
                         Ok(e) => e,
                         Err(_) => {
                             error!("Failed to read source device '{:?}', removing from '{:?}' and reloading", n, dev_name);
+                            frames.remove(n);
                             remove_dev_from_list(&sources, n);
                             continue 'device
                         }
                     };
 
                     debug!("Proxy device '{:?}' got event from '{:?}': {:?}", dev_name, n, ev);
-                    match uin.write(&[ev]) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            error!("Failed to write event to '{:?}': {:?}", dev_name, e);
-                        }
+                    if src.paused {
+                        continue
+                    }
+                    if is_syn_dropped(&ev) {
+                        // Poller resyncs source state after it, so incomplete frame is useless
+                        warn!("Source '{:?}' dropped events, discarding its incomplete frame", n);
+                        frames.remove(n);
+                        continue
+                    }
+                    if ev.type_ == sys::EV_LED as u16 {
+                        // LEDs are owned by consumer of virtual device, sources only echo them
+                        continue
+                    }
+                    if ev.type_ == sys::EV_ABS as u16 {
+                        scale_abs(&mut ev, &src.abs_ranges, &abs_ranges);
+                    }
+                    let frame = frames.entry(n.to_owned()).or_insert_with(|| Frame {
+                        events: Vec::with_capacity(limits.max_events + 1),
+                        started: Instant::now(),
+                    });
+                    frame.events.push(ev);
+
+                    if is_syn_report(&ev) {
+                        let frame = frames.remove(n).unwrap();
+                        router.write_source_frame(n, &frame.events);
+                    } else if frame.events.len() >= limits.max_events {
+                        warn!("Frame from '{:?}' exceeds {:} events, flushing", n, limits.max_events);
+                        let mut frame = frames.remove(n).unwrap();
+                        frame.events.push(syn_report());
+                        router.write_source_frame(n, &frame.events);
                     }
                 }
             }
+            router.shutdown();
+            info!("Event loop for proxy device '{:}' has finished", dev_name);
         });
 
         Ok(dev)
     }
 
-    pub fn add_source_dev<P: AsRef<Path> + Debug + ToString>(&self, path: P) -> io::Result<()> {
-        let rx = device_poller(path.to_string(), 64)?;
+    pub fn add_source_dev<P: AsRef<Path> + Debug + ToString>(&self, path: P, grab: GrabMode) -> io::Result<()> {
+        if self.sources.lock().unwrap().iter().any(|(name, _)| name == &path.to_string()) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, format!("source dev '{:}' is already added", path.to_string())))
+        }
+        let src = device_poller(path.to_string(), 64, grab)?;
         match self.sources.lock(){
             Ok(mut srcs) => {
-                info!("Added new source dev '{:?}'", path);
-                srcs.push((path.to_string(), rx));
-                // Careful here, we're still holding sources mutex and trying to send reload signal
-                self.ch_reload.0.send(true).unwrap();
+                let output = &self.outputs[self.active.load(Ordering::SeqCst)];
+                let leds = led_frame(&output.leds.lock().unwrap());
+                if leds.len() > 1 {
+                    if let Err(e) = src.write(&leds) {
+                        debug!("Failed to set LEDs of '{:?}': {:?}", path, e);
+                    }
+                }
+                for effect in output.effects.lock().unwrap().0.values() {
+                    if let Err(e) = src.upload_effect(effect) {
+                        warn!("Failed to upload effect {:} to '{:?}': {:?}", effect.id, path, e);
+                    }
+                }
+                if !self.grabbed.load(Ordering::SeqCst) {
+                    if let Err(e) = src.grab(false) {
+                        warn!("Failed to ungrab '{:?}': {:?}", path, e);
+                    }
+                }
+                info!("Added new source dev '{:?}' to '{:?}'", path, self.name);
+                srcs.push((path.to_string(), src));
+                self.request_reload();
                 Ok(())
             },
             Err(err) => {
                 error!("Failed to add source dev: {:?}", err);
-                Err(io::Error::new(ErrorKind::Other, err.to_string()))
+                Err(io::Error::new(io::ErrorKind::Other, err.to_string()))
             }
         }
     }
 
+    /// Stops polling of the source device and releases its grab
+    pub fn remove_source_dev<P: AsRef<Path> + Debug + ToString>(&self, path: P) -> io::Result<()> {
+        let src = {
+            let mut srcs = self.sources.lock()
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            let idx = srcs.iter().position(|(name, _)| name == &path.to_string())
+                .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no source dev '{:}'", path.to_string())))?;
+            let src = srcs.remove(idx);
+            info!("Removed source dev '{:?}' from '{:?}', {:} source(s) left", path, self.name, srcs.len());
+            self.request_reload();
+            src
+        };
+        // Drop it only after event loop was told to forget about it
+        std::mem::drop(src);
+        Ok(())
+    }
+
+    /// Stops proxying events of the source device or resumes it, device stays grabbed
+    pub fn pause_source<P: AsRef<Path> + Debug + ToString>(&self, path: P, paused: bool) -> io::Result<()> {
+        let srcs = self.sources.lock()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        let (_, src) = srcs.iter().find(|(name, _)| name == &path.to_string())
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no source dev '{:}'", path.to_string())))?;
+        info!("{:} source dev '{:?}' of '{:?}'", if paused { "Pausing" } else { "Resuming" }, path, self.name);
+        src.set_paused(paused);
+        self.request_reload();
+        Ok(())
+    }
+
+    /// Tells event loop to re-read sources. Sources mutex is held here and
+    /// event loop may wait for it, so the signal is dropped if one is pending.
+    fn request_reload(&self) {
+        if let Err(TrySendError::Disconnected(_)) = self.ch_reload.0.try_send(true) {
+            debug!("Event loop of '{:?}' is gone, not reloading sources", self.name);
+        }
+    }
+
+    /// Paths of source devices and whether they're paused
+    pub fn sources(&self) -> Vec<(String, bool)> {
+        self.sources.lock().unwrap().iter()
+            .map(|(name, src)| (name.to_owned(), src.is_paused()))
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn num_sources(&self) -> usize {
         self.sources.lock().unwrap().len()
     }
 
+    /// Path of the virtual device that currently receives events
     pub fn dev_path(&self) -> &str {
-        &self.devpath
+        &self.outputs[self.active.load(Ordering::SeqCst)].devpath
+    }
+
+    /// Hands sources back to host (`false`) or grabs them again. Grab is
+    /// delayed until all keys of sources are released, so host doesn't see
+    /// them stuck.
+    pub fn set_grab(&self, grab: bool) {
+        self.ch_command.0.send(Command::Grab(grab)).unwrap();
+    }
+
+    pub fn is_grabbed(&self) -> bool {
+        self.grabbed.load(Ordering::SeqCst)
+    }
+
+    /// Passes events of sources to output with given index
+    pub fn switch_output(&self, output: usize) -> io::Result<()> {
+        if output >= self.outputs.len() {
+            return Err(io::Error::new(ErrorKind::NotFound, format!("no output {:} of '{:}'", output, self.name)))
+        }
+        self.ch_command.0.send(Command::Switch(output)).unwrap();
+        Ok(())
+    }
+
+    /// Writes synthetic events to active output between frames of sources
+    pub fn inject(&self, spec: &inject::InjectSpec) -> io::Result<()> {
+        let events = spec.resolve()?;
+        inject::validate(&self.caps, &events)?;
+        info!("Injecting {:} event(s) into '{:?}'", events.len(), self.name);
+        self.ch_command.0.send(Command::Inject(events)).unwrap();
+        Ok(())
+    }
+
+    /// Types `text` on active output key by key, waiting `delay` after each one
+    pub fn type_text(&self, text: &str, layout: &Layout, delay: Duration) -> io::Result<()> {
+        let strokes = layout.strokes(text)?;
+        for stroke in &strokes {
+            inject::validate(&self.caps, stroke)?;
+        }
+        info!("Typing {:} character(s) into '{:?}'", strokes.len(), self.name);
+        // Strokes are sent one by one, so frames of sources are not delayed
+        for stroke in strokes {
+            self.ch_command.0.send(Command::Inject(stroke)).unwrap();
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// Index of output that receives events
+    pub fn active_output(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Names and paths of all virtual devices
+    pub fn outputs(&self) -> Vec<(&str, &str)> {
+        self.outputs.iter().map(|o| (o.name.as_str(), o.devpath.as_str())).collect()
+    }
+}
+
+impl Drop for Simple {
+    fn drop(&mut self) {
+        info!("Shutting down proxy device '{:?}'", self.name);
+        self.ch_command.0.send(Command::Shutdown).unwrap();
+    }
+}
+
+/// State of event loop: virtual devices with the active one and state of
+/// sources needed to switch between them
+struct Router {
+    dev_name: String,
+    outputs: Vec<Arc<UInputHandle<File>>>,
+    leds: Vec<Arc<Mutex<HashMap<u16, i32>>>>,
+    effects: Vec<Arc<Mutex<Effects>>>,
+    active: Arc<AtomicUsize>,
+    grabbed: Arc<AtomicBool>,
+    // Grab requested, but some keys are still pressed
+    pending_grab: bool,
+    hotkeys: Hotkeys,
+    sources: SourceList,
+    // Keys and buttons pressed on each source
+    pressed: HashMap<String, HashSet<u16>>,
+    // Keys and buttons held down on virtual device by each source
+    held_keys: HashMap<String, HashSet<u16>>,
+    slot_map: Option<SlotMap>,
+}
+
+impl Router {
+    fn write(&self, frame: &[input_event]) {
+        let uin = &self.outputs[self.active.load(Ordering::SeqCst)];
+        write_frame(uin, &self.dev_name, frame);
+    }
+
+    /// Writes complete frame of a source, keeping track of its keys and contacts
+    fn write_source_frame(&mut self, source: &str, frame: &[input_event]) {
+        track_keys(self.pressed.entry(source.to_owned()).or_default(), frame);
+        let switch = chord_pressed(&self.hotkeys.switch, frame, &self.pressed);
+        let toggle_grab = chord_pressed(&self.hotkeys.grab, frame, &self.pressed);
+
+        // Ungrabbed sources are used by host directly
+        if self.grabbed.load(Ordering::SeqCst) {
+            let held = self.held_keys.entry(source.to_owned()).or_default();
+            match self.slot_map.as_mut() {
+                Some(slots) => {
+                    let mut frame = slots.remap(source, frame);
+                    track_keys(held, &frame);
+                    slots.sync_tools(&mut frame);
+                    self.write(&frame);
+                },
+                None => {
+                    track_keys(held, frame);
+                    self.write(frame);
+                },
+            }
+            if switch {
+                self.switch((self.active.load(Ordering::SeqCst) + 1) % self.outputs.len());
+            }
+        }
+
+        if toggle_grab {
+            self.set_grab(!self.grabbed.load(Ordering::SeqCst));
+        } else {
+            self.try_grab();
+        }
+    }
+
+    fn set_grab(&mut self, grab: bool) {
+        self.pending_grab = grab;
+        if grab {
+            self.try_grab();
+        } else if self.grabbed.load(Ordering::SeqCst) {
+            info!("Handing sources of '{:?}' back to host", self.dev_name);
+            self.release_all();
+            let srcs = self.sources.lock().unwrap();
+            self.grabbed.store(false, Ordering::SeqCst);
+            for (n, src) in srcs.iter() {
+                if let Err(e) = src.grab(false) {
+                    warn!("Failed to ungrab '{:?}': {:?}", n, e);
+                }
+            }
+        }
+    }
+
+    /// Grabs sources if it was requested and no keys are pressed
+    fn try_grab(&mut self) {
+        if !self.pending_grab || self.pressed.values().any(|p| !p.is_empty()) {
+            return
+        }
+        self.pending_grab = false;
+        if self.grabbed.load(Ordering::SeqCst) {
+            return
+        }
+        info!("Grabbing sources of '{:?}' back from host", self.dev_name);
+        let srcs = self.sources.lock().unwrap();
+        self.grabbed.store(true, Ordering::SeqCst);
+        // Host could change LEDs meanwhile
+        let leds = led_frame(&self.leds[self.active.load(Ordering::SeqCst)].lock().unwrap());
+        for (n, src) in srcs.iter() {
+            if let Err(e) = src.grab(true) {
+                warn!("Failed to grab '{:?}': {:?}", n, e);
+            }
+            if leds.len() > 1 {
+                if let Err(e) = src.write(&leds) {
+                    debug!("Failed to set LEDs of '{:?}': {:?}", n, e);
+                }
+            }
+        }
+    }
+
+    fn inject(&mut self, events: &[input_event]) {
+        // Injected keys are released on switch and shutdown like ones of sources
+        track_keys(self.held_keys.entry(INJECTED.to_owned()).or_default(), events);
+        // Each frame is written separately, so pressed keys are seen before released ones
+        for frame in events.split_inclusive(is_syn_report) {
+            self.write(frame);
+        }
+    }
+
+    /// Releases sources and destroys virtual devices, their readers stop after that
+    fn shutdown(&mut self) {
+        self.release_all();
+        self.sources.lock().unwrap().clear();
+        for uin in &self.outputs {
+            if let Err(e) = uin.dev_destroy() {
+                warn!("Failed to destroy output of '{:?}': {:?}", self.dev_name, e);
+            }
+        }
+    }
+
+    /// Releases all keys and contacts on active output
+    fn release_all(&mut self) {
+        let release: HashSet<u16> = self.held_keys.values_mut().flat_map(|h| h.drain()).collect();
+        if !release.is_empty() {
+            self.write(&key_release_frame(&release.into_iter().collect::<Vec<u16>>()));
+        }
+        if let Some(slots) = self.slot_map.as_mut() {
+            let lifted = slots.release(|_| false);
+            if !lifted.is_empty() {
+                self.write(&lifted);
+            }
+        }
+    }
+
+    /// Releases keys and contacts of sources for which `present` is false
+    fn release_gone<F: Fn(&str) -> bool>(&mut self, present: F) {
+        self.pressed.retain(|n, _| present(n));
+        self.try_grab();
+        // Keys held by other sources are kept
+        let gone: Vec<String> = self.held_keys.keys()
+            .filter(|n| *n != INJECTED && !present(n))
+            .map(|n| n.to_owned())
+            .collect();
+        for n in gone {
+            let keys = self.held_keys.remove(&n).unwrap();
+            let release: Vec<u16> = keys.into_iter()
+                .filter(|k| !self.held_keys.values().any(|h| h.contains(k)))
+                .collect();
+            if !release.is_empty() {
+                info!("Releasing {:} key(s) held by removed source '{:?}'", release.len(), n);
+                self.write(&key_release_frame(&release));
+            }
+        }
+        if let Some(slots) = self.slot_map.as_mut() {
+            let lifted = slots.release(present);
+            if !lifted.is_empty() {
+                info!("Lifting contacts of removed sources from '{:?}'", self.dev_name);
+                self.write(&lifted);
+            }
+        }
+    }
+
+    /// Makes `next` the active output. All keys and contacts are released on
+    /// the old one and sources get LEDs and effects of the new one.
+    fn switch(&mut self, next: usize) {
+        let prev = self.active.load(Ordering::SeqCst);
+        if next == prev {
+            return
+        }
+        info!("Switching '{:?}' from output {:} to {:}", self.dev_name, prev, next);
+        self.release_all();
+
+        // Readers of outputs check active one while holding sources mutex
+        let srcs = self.sources.lock().unwrap();
+        self.active.store(next, Ordering::SeqCst);
+        // New output has its own slot selected
+        if let Some(slots) = self.slot_map.as_mut() {
+            slots.reset_slot();
+        }
+        let prev_effects = self.effects[prev].lock().unwrap();
+        let next_effects = self.effects[next].lock().unwrap();
+        let leds = led_frame(&self.leds[next].lock().unwrap());
+        for (n, src) in srcs.iter() {
+            for id in prev_effects.0.keys() {
+                if let Err(e) = src.erase_effect(*id) {
+                    debug!("Failed to erase effect {:} from '{:?}': {:?}", id, n, e);
+                }
+            }
+            for effect in next_effects.0.values() {
+                if let Err(e) = src.upload_effect(effect) {
+                    warn!("Failed to upload effect {:} to '{:?}': {:?}", effect.id, n, e);
+                }
+            }
+            if leds.len() > 1 {
+                if let Err(e) = src.write(&leds) {
+                    debug!("Failed to set LEDs of '{:?}': {:?}", n, e);
+                }
+            }
+        }
+    }
+}
+
+/// Updates set of held keys with key events from frame
+fn track_keys(held: &mut HashSet<u16>, frame: &[input_event]) {
+    for ev in frame.iter().filter(|ev| ev.type_ == sys::EV_KEY as u16) {
+        if ev.value == 0 {
+            held.remove(&ev.code);
+        } else {
+            held.insert(ev.code);
+        }
+    }
+}
+
+/// Whether `frame` completes key chord, i.e. presses its last key
+fn chord_pressed(chord: &[u16], frame: &[input_event], pressed: &HashMap<String, HashSet<u16>>) -> bool {
+    !chord.is_empty()
+        && frame.iter().any(|ev| ev.type_ == sys::EV_KEY as u16 && ev.value == 1 && chord.contains(&ev.code))
+        && chord.iter().all(|k| pressed.values().any(|p| p.contains(k)))
+}
+
+/// Builds frame that releases all given keys
+fn key_release_frame(keys: &[u16]) -> Vec<input_event> {
+    let mut frame: Vec<input_event> = keys.iter()
+        .map(|k| new_event(sys::EV_KEY, *k, 0))
+        .collect();
+    frame.push(syn_report());
+    frame
+}
+
+/// Builds frame that sets all given LEDs
+fn led_frame(leds: &HashMap<u16, i32>) -> Vec<input_event> {
+    let mut frame: Vec<input_event> = leds.iter()
+        .map(|(l, v)| new_event(sys::EV_LED, *l, *v))
+        .collect();
+    frame.push(syn_report());
+    frame
+}
+
+/// Serves force-feedback effect upload request of virtual device by
+/// uploading the effect to all source devices
+fn ff_upload(uin: &UInputHandle<File>, dev_name: &str, request_id: u32, active: bool, sources: &SourceList, effects: &Mutex<Effects>) {
+    let mut upload: sys::uinput_ff_upload = unsafe { std::mem::zeroed() };
+    upload.request_id = request_id;
+    if let Err(e) = uin.ff_upload_begin(&mut upload) {
+        error!("Failed to begin effect upload for '{:?}': {:?}", dev_name, e);
+        return
+    }
+
+    // Effect is kept even if no source accepts it, it's uploaded on hotplug
+    // or when output becomes active
+    let srcs = sources.lock().unwrap();
+    if active {
+        for (n, src) in srcs.iter() {
+            if let Err(e) = src.upload_effect(&upload.effect) {
+                warn!("Failed to upload effect {:} to '{:?}': {:?}", upload.effect.id, n, e);
+            }
+        }
+    }
+    effects.lock().unwrap().0.insert(upload.effect.id, upload.effect);
+    drop(srcs);
+    upload.retval = 0;
+
+    if let Err(e) = uin.ff_upload_end(&upload) {
+        error!("Failed to end effect upload for '{:?}': {:?}", dev_name, e);
+    }
+}
+
+/// Serves force-feedback effect erase request of virtual device
+fn ff_erase(uin: &UInputHandle<File>, dev_name: &str, request_id: u32, active: bool, sources: &SourceList, effects: &Mutex<Effects>) {
+    let mut erase: sys::uinput_ff_erase = unsafe { std::mem::zeroed() };
+    erase.request_id = request_id;
+    if let Err(e) = uin.ff_erase_begin(&mut erase) {
+        error!("Failed to begin effect erase for '{:?}': {:?}", dev_name, e);
+        return
+    }
+
+    let id = erase.effect_id as i16;
+    let srcs = sources.lock().unwrap();
+    if active {
+        for (n, src) in srcs.iter() {
+            if let Err(e) = src.erase_effect(id) {
+                warn!("Failed to erase effect {:} from '{:?}': {:?}", id, n, e);
+            }
+        }
+    }
+    effects.lock().unwrap().0.remove(&id);
+    drop(srcs);
+    erase.retval = 0;
+
+    if let Err(e) = uin.ff_erase_end(&erase) {
+        error!("Failed to end effect erase for '{:?}': {:?}", dev_name, e);
+    }
+}
+
+/// Reads events sent back by consumer of virtual device: forwards LED
+/// changes and force-feedback playback to all source devices and serves
+/// force-feedback effect uploads. Only events of active output (`idx`)
+/// reach sources, state of others is kept until they become active.
+fn uinput_reader(uin: &UInputHandle<File>, dev_name: &str, idx: usize, active: &AtomicUsize, sources: &SourceList, leds: &Mutex<HashMap<u16, i32>>, effects: &Mutex<Effects>) {
+    let is_active = || active.load(Ordering::SeqCst) == idx;
+    let mut events = [new_event(0, 0, 0); 32];
+    loop {
+        let res = match uin.read(&mut events) {
+            Ok(ret) => ret,
+            // Device was destroyed on shutdown of proxy
+            Err(err) if err.raw_os_error() == Some(nix::errno::Errno::ENODEV as i32) => {
+                info!("Stopping reader of '{:?}'", dev_name);
+                return
+            },
+            Err(err) => {
+                error!("Failed to read events from '{:?}': {:?}", dev_name, err);
+                return
+            },
+        };
+
+        let mut changed = HashMap::new();
+        for ev in &events[..res] {
+            match ev.type_ as i32 {
+                sys::EV_LED => {
+                    changed.insert(ev.code, ev.value);
+                },
+                sys::EV_UINPUT => match ev.code as i32 {
+                    sys::UI_FF_UPLOAD => ff_upload(uin, dev_name, ev.value as u32, is_active(), sources, effects),
+                    sys::UI_FF_ERASE => ff_erase(uin, dev_name, ev.value as u32, is_active(), sources, effects),
+                    _ => {},
+                },
+                sys::EV_FF => {
+                    let srcs = sources.lock().unwrap();
+                    if !is_active() {
+                        continue
+                    }
+                    for (n, src) in srcs.iter() {
+                        if let Err(e) = src.play_effect(ev) {
+                            debug!("Failed to play effect {:} on '{:?}': {:?}", ev.code, n, e);
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        if changed.is_empty() {
+            continue
+        }
+        debug!("Consumer of '{:?}' changed LEDs: {:?}", dev_name, changed);
+        leds.lock().unwrap().extend(changed.iter());
+
+        let frame = led_frame(&changed);
+        let srcs = sources.lock().unwrap();
+        if !is_active() {
+            continue
+        }
+        for (n, src) in srcs.iter() {
+            if let Err(e) = src.write(&frame) {
+                debug!("Failed to set LEDs of '{:?}': {:?}", n, e);
+            }
+        }
+    }
+}
+
+/// Absolute axes which values are ids or indices rather than positions
+static UNSCALED_AXES: [u16; 4] = [
+    sys::ABS_MT_SLOT as u16,
+    sys::ABS_MT_TOOL_TYPE as u16,
+    sys::ABS_MT_BLOB_ID as u16,
+    sys::ABS_MT_TRACKING_ID as u16,
+];
+
+/// Maps value of absolute axis from the source range onto the range of virtual device
+fn scale_abs(ev: &mut input_event, src: &HashMap<u16, (i32, i32)>, dst: &HashMap<u16, (i32, i32)>) {
+    if UNSCALED_AXES.contains(&ev.code) {
+        return
+    }
+    let ((smin, smax), (dmin, dmax)) = match (src.get(&ev.code), dst.get(&ev.code)) {
+        (Some(s), Some(d)) => (*s, *d),
+        _ => return,
+    };
+    if (smin, smax) == (dmin, dmax) || smin == smax {
+        return
+    }
+    let v = (ev.value as i64).clamp(smin.min(smax) as i64, smin.max(smax) as i64);
+    let scaled = dmin as i64 + (v - smin as i64) * (dmax as i64 - dmin as i64) / (smax as i64 - smin as i64);
+    ev.value = scaled as i32;
+}
+
+/// Writes whole frame with a single call, so it is never interleaved with other sources
+fn write_frame(uin: &UInputHandle<File>, dev_name: &str, frame: &[input_event]) {
+    match uin.write(frame) {
+        Ok(_) => {},
+        Err(e) => {
+            error!("Failed to write event to '{:?}': {:?}", dev_name, e);
+        }
     }
 }
 
@@ -144,11 +933,53 @@ fn remove_dev_from_list<P: AsRef<Path> + Debug + ToString, T>(list: &Arc<Mutex<V
     };
 }
 
-// fn copy_sources<T: ToOwned, Y: Clone>(sources: &Mutex<Vec<(T, Y)>>) -> Vec<(T, Y)> {
-//     let psrc = &sources.lock().unwrap();
-//     let local_sources = Vec::with_capacity(psrc.len());
-//     for (name, s) in psrc.iter() {
-//         local_sources.push((name.to_owned(), s.clone()));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scaled(code: i32, value: i32, src: (i32, i32), dst: (i32, i32)) -> i32 {
+        let mut ev = new_event(sys::EV_ABS, code as u16, value);
+        let src = [(code as u16, src)].iter().cloned().collect();
+        let dst = [(code as u16, dst)].iter().cloned().collect();
+        scale_abs(&mut ev, &src, &dst);
+        ev.value
+    }
+
+    #[test]
+    fn range_ends_are_mapped_exactly() {
+        assert_eq!(scaled(sys::ABS_X, 0, (0, 1023), (0, 4095)), 0);
+        assert_eq!(scaled(sys::ABS_X, 1023, (0, 1023), (0, 4095)), 4095);
+        assert_eq!(scaled(sys::ABS_X, -100, (-100, 100), (0, 10)), 0);
+        assert_eq!(scaled(sys::ABS_X, 100, (-100, 100), (0, 10)), 10);
+    }
+
+    #[test]
+    fn values_are_truncated_towards_minimum() {
+        assert_eq!(scaled(sys::ABS_X, 1, (0, 3), (0, 2)), 0);
+        assert_eq!(scaled(sys::ABS_X, 2, (0, 3), (0, 2)), 1);
+        assert_eq!(scaled(sys::ABS_X, -99, (-100, 100), (0, 10)), 0);
+        assert_eq!(scaled(sys::ABS_X, 50, (0, 100), (-5, 5)), 0);
+    }
 
+    #[test]
+    fn values_outside_source_range_are_clamped() {
+        assert_eq!(scaled(sys::ABS_X, 2000, (0, 1023), (0, 4095)), 4095);
+        assert_eq!(scaled(sys::ABS_X, -5, (0, 1023), (0, 4095)), 0);
+    }
+
+    #[test]
+    fn inverted_ranges_flip_axis() {
+        assert_eq!(scaled(sys::ABS_Y, 0, (0, 100), (100, 0)), 100);
+        assert_eq!(scaled(sys::ABS_Y, 100, (0, 100), (100, 0)), 0);
+        assert_eq!(scaled(sys::ABS_Y, 25, (100, 0), (0, 100)), 75);
+        assert_eq!(scaled(sys::ABS_Y, 150, (100, 0), (0, 100)), 0);
+    }
+
+    #[test]
+    fn ids_and_degenerate_ranges_are_kept() {
+        assert_eq!(scaled(sys::ABS_MT_TRACKING_ID, 7, (0, 65535), (0, 10)), 7);
+        assert_eq!(scaled(sys::ABS_MT_SLOT, 3, (0, 9), (0, 4)), 3);
+        assert_eq!(scaled(sys::ABS_X, 5, (5, 5), (0, 100)), 5);
+        assert_eq!(scaled(sys::ABS_X, 5, (0, 10), (0, 10)), 5);
+    }
+}
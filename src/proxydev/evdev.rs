@@ -1,28 +1,337 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
 use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
 use crossbeam::channel::{Receiver, Sender};
 use crossbeam::channel;
+use input_linux::{Bitmask, EventKind, Key, LedKind, SwitchKind};
 use input_linux::evdev::EvdevHandle;
-use input_linux::sys::{input_event, timeval};
+use input_linux::sys;
+use input_linux::sys::{ff_effect, input_event, timeval};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{PollFd, PollFlags, ppoll};
+use nix::sys::signal::SigSet;
+use nix::sys::time::{TimeSpec, TimeValLike};
+
+use crate::proxydev::event::{is_syn_dropped, is_syn_report, new_event, syn_report};
 
 pub fn open_device<P: AsRef<Path> + Debug>(path: P) -> io::Result<EvdevHandle<File>> {
-    let fd = OpenOptions::new().write(false).read(true).open(path)?;
+    // Write access is needed to update LEDs and to play force-feedback
+    // effects, device is still usable for reading without them
+    let fd = match OpenOptions::new().write(true).read(true).open(&path) {
+        Ok(fd) => fd,
+        Err(e) => {
+            debug!("Failed to open '{:?}' for writing, its LEDs and force-feedback won't work: {:?}", path, e);
+            OpenOptions::new().write(false).read(true).open(&path)?
+        },
+    };
     Ok(input_linux::evdev::EvdevHandle::new(fd))
 }
 
-pub fn device_poller<P: AsRef<Path> + Debug + ToString>(path: P, size: usize) -> io::Result<Receiver<input_event>> {
+fn is_writable(dev: &EvdevHandle<File>) -> io::Result<bool> {
+    let flags = fcntl(dev.as_raw_fd(), FcntlArg::F_GETFL)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE != OFlag::O_RDONLY)
+}
+
+/// How source device is grabbed
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GrabMode {
+    /// Device is used only by proxy, adding it fails if it can't be grabbed
+    #[default]
+    Exclusive,
+    /// Device is never grabbed, host keeps receiving its events too
+    Shared,
+    /// Like `Exclusive`, but device is read without grab while somebody
+    /// else holds it, grab is retried every `GRAB_RETRY_INTERVAL`
+    Try,
+}
+
+const GRAB_RETRY_INTERVAL: i64 = 1;
+
+/// Grab of a source device, shared with its polling thread
+struct GrabState {
+    mode: GrabMode,
+    // Grab is wanted, it's false while source is handed back to host
+    wanted: bool,
+    grabbed: bool,
+}
+
+impl GrabState {
+    /// Grabs or ungrabs device as wanted, failure to grab is not an error in `Try` mode
+    fn apply(&mut self, dev: &EvdevHandle<File>, path: &str) -> io::Result<()> {
+        let grab = self.wanted && self.mode != GrabMode::Shared;
+        if grab == self.grabbed {
+            return Ok(())
+        }
+        match dev.grab(grab) {
+            Ok(()) => {
+                self.grabbed = grab;
+                Ok(())
+            },
+            Err(e) if grab && self.mode == GrabMode::Try => {
+                warn!("Failed to grab '{:?}', reading it without grab until it's released: {:?}", path, e);
+                Ok(())
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether polling thread has to retry grab
+    fn pending(&self) -> bool {
+        self.mode == GrabMode::Try && self.wanted && !self.grabbed
+    }
+}
+
+/// Key, LED and switch state of a source device, as seen through its events
+struct DeviceState {
+    keys: Bitmask<Key>,
+    leds: Bitmask<LedKind>,
+    switches: Bitmask<SwitchKind>,
+}
+
+impl DeviceState {
+    /// Reads current state of device with EVIOCGKEY, EVIOCGLED and EVIOCGSW
+    fn query(dev: &EvdevHandle<File>) -> io::Result<Self> {
+        let mut state = DeviceState {
+            keys: Bitmask::default(),
+            leds: Bitmask::default(),
+            switches: Bitmask::default(),
+        };
+        dev.key_state(&mut state.keys)?;
+        dev.led_state(&mut state.leds)?;
+        dev.switch_state(&mut state.switches)?;
+        Ok(state)
+    }
+
+    fn update(&mut self, ev: &input_event) {
+        let on = ev.value != 0;
+        match ev.type_ as i32 {
+            sys::EV_KEY => if let Ok(k) = Key::from_code(ev.code) {
+                set_bit(&mut self.keys, k, on)
+            },
+            sys::EV_LED => if let Ok(l) = LedKind::from_code(ev.code) {
+                set_bit(&mut self.leds, l, on)
+            },
+            sys::EV_SW => if let Ok(s) = SwitchKind::from_code(ev.code) {
+                set_bit(&mut self.switches, s, on)
+            },
+            _ => {},
+        }
+    }
+
+    /// Events that turn this state into `other`
+    fn diff(&self, other: &DeviceState) -> Vec<input_event> {
+        let mut events = Vec::new();
+        for k in Key::iter().filter(|k| self.keys.get(*k) != other.keys.get(*k)) {
+            events.push(new_event(sys::EV_KEY, k as u16, other.keys.get(k) as i32));
+        }
+        for l in LedKind::iter().filter(|l| self.leds.get(*l) != other.leds.get(*l)) {
+            events.push(new_event(sys::EV_LED, l as u16, other.leds.get(l) as i32));
+        }
+        for s in SwitchKind::iter().filter(|s| self.switches.get(*s) != other.switches.get(*s)) {
+            events.push(new_event(sys::EV_SW, s as u16, other.switches.get(s) as i32));
+        }
+        events
+    }
+}
+
+fn set_bit<T: input_linux::bitmask::BitmaskTrait>(mask: &mut Bitmask<T>, idx: T::Index, on: bool) {
+    if on {
+        mask.insert(idx);
+    } else {
+        mask.remove(idx);
+    }
+}
+
+/// Grabbed source device with its polling thread.
+///
+/// Dropping it stops the polling thread and releases the grab.
+pub struct SourceDev {
+    path: String,
+    events: Receiver<input_event>,
+    dev: Arc<EvdevHandle<File>>,
+    // Polling thread exits as soon as write end of this pipe is closed
+    _stop: File,
+    // Whether device supports force-feedback
+    ff: bool,
+    // Effect ids of virtual device -> effect ids of this device
+    effects: Mutex<HashMap<i16, i16>>,
+    // Absolute axis -> (minimum, maximum) of this device
+    abs_ranges: HashMap<u16, (i32, i32)>,
+    grab: Arc<Mutex<GrabState>>,
+    // Events of paused device are read, but not proxied
+    paused: AtomicBool,
+}
+
+impl SourceDev {
+    pub fn events(&self) -> &Receiver<input_event> {
+        &self.events
+    }
+
+    /// Ranges of absolute axes reported by the device
+    pub fn abs_ranges(&self) -> &HashMap<u16, (i32, i32)> {
+        &self.abs_ranges
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Grabs device for exclusive use or hands it back to other consumers,
+    /// devices in `Shared` mode are never grabbed
+    pub fn grab(&self, grab: bool) -> io::Result<()> {
+        let mut state = self.grab.lock().unwrap();
+        state.wanted = grab;
+        state.apply(&self.dev, &self.path)
+    }
+
+    /// Writes events to the device, e.g. to update its LEDs
+    pub fn write(&self, events: &[input_event]) -> io::Result<usize> {
+        self.dev.write(events)
+    }
+
+    /// Uploads (or updates) force-feedback effect, `effect.id` is the id
+    /// given to it by virtual device
+    pub fn upload_effect(&self, effect: &ff_effect) -> io::Result<()> {
+        if !self.ff {
+            return Ok(())
+        }
+        let mut effects = self.effects.lock().unwrap();
+        let mut e = *effect;
+        e.id = *effects.get(&effect.id).unwrap_or(&-1);
+        self.dev.send_force_feedback(&mut e)?;
+        debug!("Uploaded effect {:} to '{:?}' as {:}", effect.id, self.path, e.id);
+        effects.insert(effect.id, e.id);
+        Ok(())
+    }
+
+    pub fn erase_effect(&self, id: i16) -> io::Result<()> {
+        match self.effects.lock().unwrap().remove(&id) {
+            Some(own_id) => self.dev.erase_force_feedback(own_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Plays/stops uploaded effect or changes gain/autocenter
+    pub fn play_effect(&self, ev: &input_event) -> io::Result<()> {
+        if !self.ff {
+            return Ok(())
+        }
+        let mut ev = *ev;
+        if ev.code < FF_GAIN {
+            match self.effects.lock().unwrap().get(&(ev.code as i16)) {
+                Some(own_id) => ev.code = *own_id as u16,
+                None => return Ok(()),
+            }
+        }
+        self.dev.write(&[ev]).map(drop)
+    }
+}
+
+/// First non-effect force-feedback code, `FF_GAIN` from linux/input.h
+const FF_GAIN: u16 = 0x60;
+
+impl Debug for SourceDev {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SourceDev").field("path", &self.path).finish()
+    }
+}
+
+impl Drop for SourceDev {
+    fn drop(&mut self) {
+        debug!("Releasing source device '{:?}'", self.path);
+        // Device may be already gone, nothing to do about it
+        if let Err(e) = self.grab(false) {
+            debug!("Failed to ungrab device '{:?}': {:?}", self.path, e);
+        }
+    }
+}
+
+pub fn device_poller<P: AsRef<Path> + Debug + ToString>(path: P, size: usize, mode: GrabMode) -> io::Result<SourceDev> {
     let (tx, rx): (Sender<input_event>, Receiver<input_event>) = channel::bounded(size);
 
-    let dev = open_device(&path)?;
-    dev.grab(true)?;
+    let dev = Arc::new(open_device(&path)?);
+    let mut grab = GrabState { mode, wanted: true, grabbed: false };
+    grab.apply(&dev, &path.to_string())?;
+    let grab = Arc::new(Mutex::new(grab));
+    let mut state = DeviceState::query(&dev)?;
+    let (stop_rx, stop_tx) = nix::unistd::pipe()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let (stop_rx, stop_tx) = unsafe { (File::from_raw_fd(stop_rx), File::from_raw_fd(stop_tx)) };
+
+    let event_bits = dev.event_bits()?;
+    let mut abs_ranges = HashMap::new();
+    if event_bits.get(EventKind::Absolute) {
+        for a in dev.absolute_bits()?.iter() {
+            let info = dev.absolute_info(a)?;
+            abs_ranges.insert(a as u16, (info.minimum, info.maximum));
+        }
+    }
+
     let path: String = path.to_string();
+    let mut ff = event_bits.get(EventKind::ForceFeedback);
+    if ff && !is_writable(&dev)? {
+        warn!("Source dev '{:?}' is opened read-only, its force-feedback is disabled", path);
+        ff = false;
+    }
+    let source = SourceDev {
+        path: path.clone(),
+        events: rx,
+        dev: dev.clone(),
+        _stop: stop_tx,
+        ff,
+        effects: Mutex::new(HashMap::new()),
+        abs_ranges,
+        grab: grab.clone(),
+        paused: AtomicBool::new(false),
+    };
+
     thread::spawn(move || {
         let mut events: [input_event; 128] = [input_event{time:timeval{tv_usec:0,tv_sec:0},code:0,type_:0,value:0}; 128];
+        // Set after SYN_DROPPED, until the next SYN_REPORT
+        let mut dropped = false;
         loop {
+            let mut fds = [
+                PollFd::new(dev.as_raw_fd(), PollFlags::POLLIN),
+                PollFd::new(stop_rx.as_raw_fd(), PollFlags::POLLIN),
+            ];
+            // Wake up periodically to retry grab
+            let timeout = if grab.lock().unwrap().pending() {
+                Some(TimeSpec::seconds(GRAB_RETRY_INTERVAL))
+            } else {
+                None
+            };
+            if let Err(err) = ppoll(&mut fds, timeout, SigSet::empty()) {
+                debug!("Failed to poll device '{:?}': {:?}", path, err);
+                continue
+            }
+            if fds[1].revents().map_or(false, |r| !r.is_empty()) {
+                info!("Stopping poller for device '{:?}'", path);
+                return
+            }
+            {
+                let mut state = grab.lock().unwrap();
+                if state.pending() && dev.grab(true).is_ok() {
+                    info!("Grabbed device '{:?}' after it was released", path);
+                    state.grabbed = true;
+                }
+            }
+            if fds[0].revents().map_or(true, |r| r.is_empty()) {
+                continue
+            }
+
             let res = match dev.read(&mut events) {
                 Ok(ret) => ret,
                 Err(err) => {
@@ -30,7 +339,36 @@ pub fn device_poller<P: AsRef<Path> + Debug + ToString>(path: P, size: usize) ->
                     return
                 },
             };
+            let mut out = Vec::with_capacity(res);
             for ev in &events[..res] {
+                if dropped {
+                    // Events up to the SYN_REPORT are incomplete, replace them with actual state
+                    if is_syn_report(ev) {
+                        dropped = false;
+                        match DeviceState::query(&dev) {
+                            Ok(actual) => {
+                                let diff = state.diff(&actual);
+                                info!("Resynced device '{:?}' after SYN_DROPPED, {:} event(s) changed", path, diff.len());
+                                out.extend(diff);
+                                out.push(syn_report());
+                                state = actual;
+                            },
+                            Err(err) => {
+                                error!("Failed to query state of device '{:?}': {:?}", path, err);
+                            },
+                        }
+                    }
+                    continue
+                }
+                if is_syn_dropped(ev) {
+                    warn!("Device '{:?}' dropped events, resyncing on next SYN_REPORT", path);
+                    dropped = true;
+                } else {
+                    state.update(ev);
+                }
+                out.push(*ev);
+            }
+            for ev in &out {
                 match tx.send(*ev) {
                     Ok(_) => {
                         debug!("Sent message from '{:}'", path);
@@ -44,6 +382,5 @@ pub fn device_poller<P: AsRef<Path> + Debug + ToString>(path: P, size: usize) ->
         }
     });
 
-    Ok(rx)
+    Ok(source)
 }
-
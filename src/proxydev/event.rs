@@ -0,0 +1,18 @@
+use input_linux::sys;
+use input_linux::sys::{input_event, timeval};
+
+pub fn new_event(type_: i32, code: u16, value: i32) -> input_event {
+    input_event{time:timeval{tv_usec:0,tv_sec:0}, type_: type_ as u16, code, value}
+}
+
+pub fn syn_report() -> input_event {
+    new_event(sys::EV_SYN, sys::SYN_REPORT as u16, 0)
+}
+
+pub fn is_syn_report(ev: &input_event) -> bool {
+    ev.type_ == sys::EV_SYN as u16 && ev.code == sys::SYN_REPORT as u16
+}
+
+pub fn is_syn_dropped(ev: &input_event) -> bool {
+    ev.type_ == sys::EV_SYN as u16 && ev.code == sys::SYN_DROPPED as u16
+}
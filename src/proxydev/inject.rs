@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind;
+
+use input_linux::sys;
+use input_linux::sys::input_event;
+
+use crate::proxydev::caps::{key_codes, Capabilities};
+use crate::proxydev::codes;
+use crate::proxydev::event::{is_syn_report, new_event, syn_report};
+
+/// Synthetic input written to proxy device, all parts are optional and
+/// written in order: `keys`, `events`, `rel`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct InjectSpec {
+    /// Keys pressed in given order and released in reverse one,
+    /// e.g. `["KEY_LEFTCTRL", "KEY_LEFTALT", "KEY_DELETE"]`
+    pub keys: Option<Vec<String>>,
+    /// Raw events, `SYN_REPORT` is added after the last one if missing
+    pub events: Option<Vec<EventSpec>>,
+    /// Relative motion by axis, e.g. `{"REL_X": 10, "REL_Y": -5}`
+    pub rel: Option<BTreeMap<String, i32>>,
+}
+
+/// Single event, type and code are given by names (`EV_KEY`, `KEY_A`) or numbers
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventSpec {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub code: String,
+    pub value: i32,
+}
+
+impl InjectSpec {
+    pub fn resolve(&self) -> io::Result<Vec<input_event>> {
+        let mut events = Vec::new();
+        if let Some(keys) = &self.keys {
+            let keys = key_codes(keys)?;
+            events.extend(keys.iter().map(|k| new_event(sys::EV_KEY, *k, 1)));
+            events.push(syn_report());
+            events.extend(keys.iter().rev().map(|k| new_event(sys::EV_KEY, *k, 0)));
+            events.push(syn_report());
+        }
+        if let Some(specs) = &self.events {
+            for e in specs {
+                events.push(e.resolve()?);
+            }
+            if !events.last().map_or(false, is_syn_report) {
+                events.push(syn_report());
+            }
+        }
+        if let Some(rel) = &self.rel {
+            for (axis, value) in rel {
+                events.push(EventSpec { type_: "EV_REL".to_owned(), code: axis.to_owned(), value: *value }.resolve()?);
+            }
+            events.push(syn_report());
+        }
+        if events.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "nothing to inject"))
+        }
+        Ok(events)
+    }
+}
+
+impl EventSpec {
+    pub fn resolve(&self) -> io::Result<input_event> {
+        let type_ = code(&codes::EVENTS, &self.type_)?;
+        let table: &[(&str, u16)] = match type_ as i32 {
+            sys::EV_SYN => &codes::SYNS,
+            sys::EV_KEY => &codes::KEYS,
+            sys::EV_REL => &codes::RELATIVE,
+            sys::EV_ABS => &codes::ABSOLUTE,
+            sys::EV_MSC => &codes::MISC,
+            sys::EV_SW => &codes::SWITCHES,
+            sys::EV_LED => &codes::LEDS,
+            _ => &[],
+        };
+        Ok(new_event(type_ as i32, code(table, &self.code)?, self.value))
+    }
+}
+
+/// Code by its name or number, numbers could be hexadecimal with `0x` prefix
+fn code(table: &[(&str, u16)], name: &str) -> io::Result<u16> {
+    let number = match name.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => name.parse().ok(),
+    };
+    number.or_else(|| codes::code_by_name(table, name))
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("unknown event code '{:}'", name)))
+}
+
+/// Checks that virtual device with `caps` could report all events
+pub fn validate(caps: &Capabilities, events: &[input_event]) -> io::Result<()> {
+    for ev in events {
+        let supported = match ev.type_ as i32 {
+            // SYN_DROPPED would make readers of device resync its state
+            sys::EV_SYN => ev.code == sys::SYN_REPORT as u16,
+            sys::EV_KEY => caps.keys.contains(&ev.code),
+            sys::EV_REL => caps.relative.contains(&ev.code),
+            // Slots are assigned to contacts of sources, injected ones would clash with them
+            sys::EV_ABS => ev.code != sys::ABS_MT_SLOT as u16 && ev.code != sys::ABS_MT_TRACKING_ID as u16
+                && caps.absolute.iter().any(|a| a.axis == ev.code),
+            sys::EV_MSC => caps.misc.contains(&ev.code),
+            sys::EV_LED => caps.leds.contains(&ev.code),
+            // Switch bits are never set on proxy devices
+            sys::EV_SW => false,
+            _ => caps.events.contains(&ev.type_),
+        };
+        if !supported {
+            let name = codes::name_by_code(&codes::EVENTS, ev.type_).unwrap_or("unknown event type");
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                format!("proxy device does not support {:} code {:#x}", name, ev.code)))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxydev::caps::AbsAxis;
+
+    fn events(spec: &InjectSpec) -> Vec<(i32, i32, i32)> {
+        spec.resolve().unwrap().iter()
+            .map(|ev| (ev.type_ as i32, ev.code as i32, ev.value))
+            .collect()
+    }
+
+    fn event(type_: &str, code: &str, value: i32) -> EventSpec {
+        EventSpec { type_: type_.to_owned(), code: code.to_owned(), value }
+    }
+
+    const SYN: (i32, i32, i32) = (sys::EV_SYN, sys::SYN_REPORT, 0);
+
+    #[test]
+    fn keys_are_released_in_reverse_order() {
+        let spec = InjectSpec {
+            keys: Some(vec!["KEY_LEFTCTRL".to_owned(), "KEY_LEFTALT".to_owned(), "KEY_DELETE".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(events(&spec), vec![
+            (sys::EV_KEY, sys::KEY_LEFTCTRL, 1), (sys::EV_KEY, sys::KEY_LEFTALT, 1), (sys::EV_KEY, sys::KEY_DELETE, 1), SYN,
+            (sys::EV_KEY, sys::KEY_DELETE, 0), (sys::EV_KEY, sys::KEY_LEFTALT, 0), (sys::EV_KEY, sys::KEY_LEFTCTRL, 0), SYN,
+        ]);
+    }
+
+    #[test]
+    fn syn_report_is_added_once() {
+        let spec = InjectSpec {
+            events: Some(vec![event("EV_KEY", "KEY_A", 1)]),
+            ..Default::default()
+        };
+        assert_eq!(events(&spec), vec![(sys::EV_KEY, sys::KEY_A, 1), SYN]);
+
+        let spec = InjectSpec {
+            events: Some(vec![event("EV_KEY", "KEY_A", 1), event("EV_SYN", "SYN_REPORT", 0)]),
+            ..Default::default()
+        };
+        assert_eq!(events(&spec), vec![(sys::EV_KEY, sys::KEY_A, 1), SYN]);
+    }
+
+    #[test]
+    fn parts_are_written_in_order() {
+        let mut rel = BTreeMap::new();
+        rel.insert("REL_Y".to_owned(), -5);
+        rel.insert("REL_X".to_owned(), 10);
+        let spec = InjectSpec {
+            keys: Some(vec!["BTN_LEFT".to_owned()]),
+            events: Some(vec![event("EV_REL", "REL_WHEEL", 1)]),
+            rel: Some(rel),
+        };
+        assert_eq!(events(&spec), vec![
+            (sys::EV_KEY, sys::BTN_LEFT, 1), SYN, (sys::EV_KEY, sys::BTN_LEFT, 0), SYN,
+            (sys::EV_REL, sys::REL_WHEEL, 1), SYN,
+            (sys::EV_REL, sys::REL_X, 10), (sys::EV_REL, sys::REL_Y, -5), SYN,
+        ]);
+    }
+
+    #[test]
+    fn codes_by_name_or_number() {
+        assert_eq!(event("EV_KEY", "KEY_A", 1).resolve().unwrap().code, sys::KEY_A as u16);
+        assert_eq!(event("EV_KEY", "30", 1).resolve().unwrap().code, 30);
+        assert_eq!(event("0x01", "0x1e", 1).resolve().unwrap().code, 0x1e);
+        assert_eq!(event("1", "0x1E", 1).resolve().unwrap().type_, sys::EV_KEY as u16);
+        assert!(event("EV_KEY", "KEY_NOPE", 1).resolve().is_err());
+        assert!(event("EV_KEY", "0xzz", 1).resolve().is_err());
+        assert!(event("EV_NOPE", "KEY_A", 1).resolve().is_err());
+        assert!(InjectSpec::default().resolve().is_err());
+    }
+
+    #[test]
+    fn unsupported_events_are_rejected() {
+        let caps = Capabilities {
+            events: vec![sys::EV_SYN as u16, sys::EV_KEY as u16, sys::EV_ABS as u16, sys::EV_SW as u16],
+            keys: vec![sys::KEY_A as u16],
+            absolute: [sys::ABS_MT_SLOT, sys::ABS_MT_TRACKING_ID, sys::ABS_MT_POSITION_X].iter()
+                .map(|a| AbsAxis { axis: *a as u16, minimum: 0, maximum: 9, fuzz: 0, flat: 0, resolution: 0 })
+                .collect(),
+            ..Default::default()
+        };
+        let check = |type_: i32, code: i32| validate(&caps, &[new_event(type_, code as u16, 1), syn_report()]);
+        assert!(check(sys::EV_KEY, sys::KEY_A).is_ok());
+        assert!(check(sys::EV_KEY, sys::KEY_B).is_err());
+        assert!(check(sys::EV_ABS, sys::ABS_MT_POSITION_X).is_ok());
+        assert!(check(sys::EV_ABS, sys::ABS_MT_SLOT).is_err());
+        assert!(check(sys::EV_ABS, sys::ABS_MT_TRACKING_ID).is_err());
+        assert!(check(sys::EV_SYN, sys::SYN_DROPPED).is_err());
+        assert!(check(sys::EV_SW, sys::SW_LID).is_err());
+    }
+}
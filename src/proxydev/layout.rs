@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use input_linux::sys;
+use input_linux::sys::input_event;
+
+use crate::proxydev::event::{new_event, syn_report};
+
+/// Layouts shipped with the project, files in `layout_dir` take precedence
+static BUILTIN: [(&str, &str); 4] = [
+    ("us", include_str!("../../layouts/us")),
+    ("de", include_str!("../../layouts/de")),
+    ("fr", include_str!("../../layouts/fr")),
+    ("ru", include_str!("../../layouts/ru")),
+];
+
+/// XKB key names of the alphanumeric block with their evdev codes
+static XKB_KEYS: [(&str, u16); 51] = [
+    ("TLDE", sys::KEY_GRAVE as u16),
+    ("AE01", sys::KEY_1 as u16), ("AE02", sys::KEY_2 as u16), ("AE03", sys::KEY_3 as u16),
+    ("AE04", sys::KEY_4 as u16), ("AE05", sys::KEY_5 as u16), ("AE06", sys::KEY_6 as u16),
+    ("AE07", sys::KEY_7 as u16), ("AE08", sys::KEY_8 as u16), ("AE09", sys::KEY_9 as u16),
+    ("AE10", sys::KEY_0 as u16), ("AE11", sys::KEY_MINUS as u16), ("AE12", sys::KEY_EQUAL as u16),
+    ("AD01", sys::KEY_Q as u16), ("AD02", sys::KEY_W as u16), ("AD03", sys::KEY_E as u16),
+    ("AD04", sys::KEY_R as u16), ("AD05", sys::KEY_T as u16), ("AD06", sys::KEY_Y as u16),
+    ("AD07", sys::KEY_U as u16), ("AD08", sys::KEY_I as u16), ("AD09", sys::KEY_O as u16),
+    ("AD10", sys::KEY_P as u16), ("AD11", sys::KEY_LEFTBRACE as u16), ("AD12", sys::KEY_RIGHTBRACE as u16),
+    ("AC01", sys::KEY_A as u16), ("AC02", sys::KEY_S as u16), ("AC03", sys::KEY_D as u16),
+    ("AC04", sys::KEY_F as u16), ("AC05", sys::KEY_G as u16), ("AC06", sys::KEY_H as u16),
+    ("AC07", sys::KEY_J as u16), ("AC08", sys::KEY_K as u16), ("AC09", sys::KEY_L as u16),
+    ("AC10", sys::KEY_SEMICOLON as u16), ("AC11", sys::KEY_APOSTROPHE as u16),
+    ("AB01", sys::KEY_Z as u16), ("AB02", sys::KEY_X as u16), ("AB03", sys::KEY_C as u16),
+    ("AB04", sys::KEY_V as u16), ("AB05", sys::KEY_B as u16), ("AB06", sys::KEY_N as u16),
+    ("AB07", sys::KEY_M as u16), ("AB08", sys::KEY_COMMA as u16), ("AB09", sys::KEY_DOT as u16),
+    ("AB10", sys::KEY_SLASH as u16),
+    ("BKSL", sys::KEY_BACKSLASH as u16), ("AC12", sys::KEY_BACKSLASH as u16),
+    ("LSGT", sys::KEY_102ND as u16),
+    ("SPCE", sys::KEY_SPACE as u16),
+    ("RTRN", sys::KEY_ENTER as u16),
+];
+
+/// Named keysyms, single characters and `Uxxxx` are recognized without table
+static KEYSYMS: [(&str, char); 72] = [
+    ("space", ' '), ("exclam", '!'), ("quotedbl", '"'), ("numbersign", '#'),
+    ("dollar", '$'), ("percent", '%'), ("ampersand", '&'), ("apostrophe", '\''),
+    ("parenleft", '('), ("parenright", ')'), ("asterisk", '*'), ("plus", '+'),
+    ("comma", ','), ("minus", '-'), ("period", '.'), ("slash", '/'),
+    ("colon", ':'), ("semicolon", ';'), ("less", '<'), ("equal", '='),
+    ("greater", '>'), ("question", '?'), ("at", '@'), ("bracketleft", '['),
+    ("backslash", '\\'), ("bracketright", ']'), ("asciicircum", '^'), ("underscore", '_'),
+    ("grave", '`'), ("braceleft", '{'), ("bar", '|'), ("braceright", '}'),
+    ("asciitilde", '~'), ("exclamdown", '¡'), ("sterling", '£'), ("currency", '¤'),
+    ("section", '§'), ("diaeresis", '¨'), ("degree", '°'), ("twosuperior", '²'),
+    ("threesuperior", '³'), ("acute", '´'), ("mu", 'µ'), ("onesuperior", '¹'),
+    ("questiondown", '¿'), ("EuroSign", '€'),
+    ("Agrave", 'À'), ("Acircumflex", 'Â'), ("Adiaeresis", 'Ä'), ("Ccedilla", 'Ç'),
+    ("Egrave", 'È'), ("Eacute", 'É'), ("Ecircumflex", 'Ê'), ("Odiaeresis", 'Ö'),
+    ("Ugrave", 'Ù'), ("Udiaeresis", 'Ü'), ("ssharp", 'ß'), ("agrave", 'à'),
+    ("acircumflex", 'â'), ("adiaeresis", 'ä'), ("ccedilla", 'ç'), ("egrave", 'è'),
+    ("eacute", 'é'), ("ecircumflex", 'ê'), ("ediaeresis", 'ë'), ("icircumflex", 'î'),
+    ("idiaeresis", 'ï'), ("ocircumflex", 'ô'), ("odiaeresis", 'ö'), ("ugrave", 'ù'),
+    ("ucircumflex", 'û'), ("udiaeresis", 'ü'),
+];
+
+/// Modifiers held for each shift level
+static LEVELS: [&[u16]; 4] = [
+    &[],
+    &[sys::KEY_LEFTSHIFT as u16],
+    &[sys::KEY_RIGHTALT as u16],
+    &[sys::KEY_RIGHTALT as u16, sys::KEY_LEFTSHIFT as u16],
+];
+
+/// Characters typed by keys outside of the alphanumeric block
+static CONTROL_CHARS: [(char, u16); 3] = [
+    ('\n', sys::KEY_ENTER as u16),
+    ('\t', sys::KEY_TAB as u16),
+    (' ', sys::KEY_SPACE as u16),
+];
+
+/// Keyboard layout used to type text, maps characters to keys and shift
+/// levels. Read from XKB symbols files, only `key <NAME> { [ ... ] };`
+/// definitions are used, dead keys are not supported.
+pub struct Layout {
+    chars: HashMap<char, (u16, usize)>,
+}
+
+impl Layout {
+    /// Loads layout by its name from `dir` or from layouts shipped with the project
+    pub fn load(name: &str, dir: Option<&str>) -> io::Result<Layout> {
+        if name.contains('/') {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid layout name '{:}'", name)))
+        }
+        if let Some(dir) = dir {
+            let path = Path::new(dir).join(name);
+            if path.exists() {
+                return Ok(Layout::parse(&fs::read_to_string(path)?))
+            }
+        }
+        BUILTIN.iter().find(|(n, _)| *n == name)
+            .map(|(_, symbols)| Layout::parse(symbols))
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no keyboard layout '{:}'", name)))
+    }
+
+    pub fn parse(symbols: &str) -> Layout {
+        let mut chars = HashMap::new();
+        for line in symbols.lines() {
+            let line = line.split("//").next().unwrap_or_default().trim();
+            let key = match line.strip_prefix("key <").and_then(|l| l.split_once('>')) {
+                Some((key, _)) => key,
+                None => continue,
+            };
+            let code = match XKB_KEYS.iter().find(|(n, _)| *n == key) {
+                Some((_, code)) => *code,
+                None => {
+                    debug!("Skipping unsupported key '{:?}' of layout", key);
+                    continue
+                },
+            };
+            let levels = match line.split_once('[').and_then(|(_, l)| l.split_once(']')) {
+                Some((levels, _)) => levels,
+                None => continue,
+            };
+            for (level, sym) in levels.split(',').map(str::trim).enumerate().take(LEVELS.len()) {
+                // Keep the simplest way to type a character
+                if let Some(c) = keysym_char(sym) {
+                    chars.entry(c).or_insert((code, level));
+                }
+            }
+        }
+        for (c, code) in CONTROL_CHARS.iter() {
+            chars.entry(*c).or_insert((*code, 0));
+        }
+        Layout{chars}
+    }
+
+    /// Key strokes typing `text`, one frame of presses followed by frame of
+    /// releases for each character
+    pub fn strokes(&self, text: &str) -> io::Result<Vec<Vec<input_event>>> {
+        text.chars().map(|c| {
+            let (code, level) = self.chars.get(&c)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("character {:?} is not in layout", c)))?;
+            let mods = LEVELS[*level];
+            let mut events: Vec<input_event> = mods.iter().chain(std::iter::once(code))
+                .map(|k| new_event(sys::EV_KEY, *k, 1))
+                .collect();
+            events.push(syn_report());
+            events.extend(std::iter::once(code).chain(mods.iter().rev())
+                .map(|k| new_event(sys::EV_KEY, *k, 0)));
+            events.push(syn_report());
+            Ok(events)
+        }).collect()
+    }
+}
+
+fn keysym_char(sym: &str) -> Option<char> {
+    let mut chars = sym.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c)
+    }
+    if let Some(hex) = sym.strip_prefix('U') {
+        if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32) {
+            return Some(c)
+        }
+    }
+    KEYSYMS.iter().find(|(n, _)| *n == sym).map(|(_, c)| *c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: i32 = sys::KEY_LEFTSHIFT;
+    const ALTGR: i32 = sys::KEY_RIGHTALT;
+
+    /// Keys pressed to type `c`, modifiers first
+    fn keys(layout: &str, c: char) -> Vec<i32> {
+        let layout = Layout::load(layout, None).unwrap();
+        let strokes = layout.strokes(&c.to_string()).unwrap();
+        assert_eq!(strokes.len(), 1);
+        strokes[0].iter()
+            .filter(|ev| ev.type_ == sys::EV_KEY as u16 && ev.value == 1)
+            .map(|ev| ev.code as i32)
+            .collect()
+    }
+
+    #[test]
+    fn stroke_presses_and_releases_keys() {
+        let layout = Layout::load("us", None).unwrap();
+        let strokes = layout.strokes("A").unwrap();
+        let events: Vec<(u16, u16, i32)> = strokes[0].iter().map(|ev| (ev.type_, ev.code, ev.value)).collect();
+        let key = |code: i32, value| (sys::EV_KEY as u16, code as u16, value);
+        let syn = (sys::EV_SYN as u16, sys::SYN_REPORT as u16, 0);
+        assert_eq!(events, vec![
+            key(SHIFT, 1), key(sys::KEY_A, 1), syn,
+            key(sys::KEY_A, 0), key(SHIFT, 0), syn,
+        ]);
+    }
+
+    #[test]
+    fn us_layout() {
+        assert_eq!(keys("us", 'a'), vec![sys::KEY_A]);
+        assert_eq!(keys("us", 'Z'), vec![SHIFT, sys::KEY_Z]);
+        assert_eq!(keys("us", '@'), vec![SHIFT, sys::KEY_2]);
+        assert_eq!(keys("us", '\n'), vec![sys::KEY_ENTER]);
+        assert_eq!(keys("us", '\t'), vec![sys::KEY_TAB]);
+        assert_eq!(keys("us", ' '), vec![sys::KEY_SPACE]);
+    }
+
+    #[test]
+    fn de_layout() {
+        assert_eq!(keys("de", 'z'), vec![sys::KEY_Y]);
+        assert_eq!(keys("de", 'ä'), vec![sys::KEY_APOSTROPHE]);
+        assert_eq!(keys("de", 'Ä'), vec![SHIFT, sys::KEY_APOSTROPHE]);
+        assert_eq!(keys("de", '?'), vec![SHIFT, sys::KEY_MINUS]);
+        assert_eq!(keys("de", '@'), vec![ALTGR, sys::KEY_Q]);
+        assert_eq!(keys("de", '€'), vec![ALTGR, sys::KEY_E]);
+        assert_eq!(keys("de", '¿'), vec![ALTGR, SHIFT, sys::KEY_MINUS]);
+    }
+
+    #[test]
+    fn fr_layout() {
+        assert_eq!(keys("fr", 'a'), vec![sys::KEY_Q]);
+        assert_eq!(keys("fr", 'é'), vec![sys::KEY_2]);
+        assert_eq!(keys("fr", '2'), vec![SHIFT, sys::KEY_2]);
+        assert_eq!(keys("fr", '~'), vec![ALTGR, sys::KEY_2]);
+        assert_eq!(keys("fr", 'ù'), vec![sys::KEY_APOSTROPHE]);
+    }
+
+    #[test]
+    fn ru_layout() {
+        assert_eq!(keys("ru", 'й'), vec![sys::KEY_Q]);
+        assert_eq!(keys("ru", 'Й'), vec![SHIFT, sys::KEY_Q]);
+        assert_eq!(keys("ru", 'ё'), vec![sys::KEY_GRAVE]);
+        assert_eq!(keys("ru", '№'), vec![SHIFT, sys::KEY_3]);
+    }
+
+    #[test]
+    fn unmapped_characters_are_rejected() {
+        let us = Layout::load("us", None).unwrap();
+        assert!(us.strokes("ä").is_err());
+        assert!(us.strokes("ok ä").is_err());
+        let ru = Layout::load("ru", None).unwrap();
+        assert!(ru.strokes("q").is_err());
+    }
+
+    #[test]
+    fn dead_keys_are_not_mapped() {
+        let layout = Layout::parse("key <AE12> { [ dead_acute, dead_grave ] };\nkey <AD01> { [ q, Q, dead_circumflex ] };");
+        assert!(layout.strokes("´").is_err());
+        assert!(layout.strokes("`").is_err());
+        assert!(layout.strokes("^").is_err());
+        assert!(layout.strokes("Q").is_ok());
+    }
+
+    #[test]
+    fn layout_names() {
+        assert_eq!(Layout::load("../us", None).err().map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        assert_eq!(Layout::load("/etc/passwd", Some("/tmp")).err().map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        assert_eq!(Layout::load("xx", None).err().map(|e| e.kind()), Some(ErrorKind::NotFound));
+        assert!(Layout::load("us", Some("/nonexistent")).is_ok());
+    }
+}
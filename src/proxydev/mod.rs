@@ -1,7 +1,24 @@
 pub use device_simple::Simple;
 pub use device_simple::SimpleDeviceClass;
+pub use device_simple::FrameLimits;
+pub use device_simple::KeyProfile;
+pub use device_simple::Hotkeys;
+pub use caps::Capabilities;
+pub use caps::AbsAxis;
+pub use caps::AbsAxisSpec;
+pub use caps::key_codes;
+pub use evdev::open_device;
+pub use evdev::GrabMode;
+pub use inject::InjectSpec;
+pub use layout::Layout;
 
 mod uinput;
+mod caps;
+mod codes;
 mod device_simple;
 mod evdev;
+mod event;
+mod inject;
+mod layout;
+mod mt;
 
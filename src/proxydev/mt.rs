@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+
+use input_linux::sys;
+use input_linux::sys::input_event;
+
+use crate::proxydev::event::{is_syn_report, new_event, syn_report};
+
+/// Keys that report number of fingers on touchpad, they're set by `SlotMap`
+/// from contacts of all sources
+static TOOL_KEYS: [u16; 6] = [
+    sys::BTN_TOUCH as u16,
+    sys::BTN_TOOL_FINGER as u16,
+    sys::BTN_TOOL_DOUBLETAP as u16,
+    sys::BTN_TOOL_TRIPLETAP as u16,
+    sys::BTN_TOOL_QUADTAP as u16,
+    sys::BTN_TOOL_QUINTTAP as u16,
+];
+
+/// Highest `ABS_MT_SLOT` accepted for virtual device, real ones have few dozens of slots at most
+pub const MAX_SLOT: i32 = 255;
+
+/// Single-touch axes with multitouch axes they are emulated from
+static POINTER_AXES: [(u16, u16); 3] = [
+    (sys::ABS_X as u16, sys::ABS_MT_POSITION_X as u16),
+    (sys::ABS_Y as u16, sys::ABS_MT_POSITION_Y as u16),
+    (sys::ABS_PRESSURE as u16, sys::ABS_MT_PRESSURE as u16),
+];
+
+/// Maps multitouch (protocol B) slots and tracking ids of several sources
+/// onto slots of the virtual device, so contacts of different sources never
+/// share a slot. Single-touch axes of sources are replaced with ones of the
+/// lowest occupied slot.
+///
+/// Contacts that do not fit into free slots are dropped.
+pub struct SlotMap {
+    // Virtual slot -> source and its slot
+    slots: Vec<Option<(String, i32)>>,
+    // Last values of POINTER_AXES reported for each virtual slot
+    values: Vec<[Option<i32>; 3]>,
+    // Current slot of each source
+    current: HashMap<String, i32>,
+    // Slot last selected on the virtual device
+    last_slot: i32,
+    next_id: i32,
+    // Last reported state of TOOL_KEYS
+    tools: [bool; 6],
+    // Last reported values of POINTER_AXES
+    pointer: [Option<i32>; 3],
+}
+
+impl SlotMap {
+    pub fn new(slots: usize) -> SlotMap {
+        SlotMap {
+            slots: vec![None; slots],
+            values: vec![[None; 3]; slots],
+            current: HashMap::new(),
+            last_slot: 0,
+            next_id: 0,
+            tools: [false; 6],
+            pointer: [None; 3],
+        }
+    }
+
+    /// Rewrites slots and tracking ids of a frame from `source`, finger count
+    /// keys and single-touch axes of the source are dropped
+    pub fn remap(&mut self, source: &str, frame: &[input_event]) -> Vec<input_event> {
+        let mut out = Vec::with_capacity(frame.len());
+        for ev in frame {
+            if ev.type_ == sys::EV_KEY as u16 && TOOL_KEYS.contains(&ev.code) {
+                continue
+            }
+            if ev.type_ == sys::EV_ABS as u16 && POINTER_AXES.iter().any(|(st, _)| *st == ev.code) {
+                continue
+            }
+            if ev.type_ != sys::EV_ABS as u16 || !is_mt_axis(ev.code) {
+                out.push(*ev);
+                continue
+            }
+            if ev.code == sys::ABS_MT_SLOT as u16 {
+                self.current.insert(source.to_owned(), ev.value);
+                continue
+            }
+
+            let src_slot = *self.current.get(source).unwrap_or(&0);
+            let mapped = self.slots.iter()
+                .position(|s| s.as_ref().map_or(false, |(n, slot)| n == source && *slot == src_slot));
+            if ev.code == sys::ABS_MT_TRACKING_ID as u16 {
+                if ev.value < 0 {
+                    if let Some(slot) = mapped {
+                        self.select(slot, &mut out);
+                        out.push(*ev);
+                        self.slots[slot] = None;
+                    }
+                    continue
+                }
+                let slot = match mapped.or_else(|| self.slots.iter().position(|s| s.is_none())) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("No free slot for contact from '{:?}', dropping it", source);
+                        continue
+                    },
+                };
+                if mapped.is_none() {
+                    self.values[slot] = [None; 3];
+                }
+                self.slots[slot] = Some((source.to_owned(), src_slot));
+                self.select(slot, &mut out);
+                out.push(new_event(sys::EV_ABS, ev.code, self.next_id));
+                self.next_id = (self.next_id + 1) & 0xffff;
+            } else if let Some(slot) = mapped {
+                if let Some(idx) = POINTER_AXES.iter().position(|(_, mt)| *mt == ev.code) {
+                    self.values[slot][idx] = Some(ev.value);
+                }
+                self.select(slot, &mut out);
+                out.push(*ev);
+            }
+        }
+        out
+    }
+
+    /// Updates finger count keys and single-touch axes of the frame from
+    /// contacts of all sources
+    pub fn sync_tools(&mut self, frame: &mut Vec<input_event>) {
+        let contacts = self.slots.iter().filter(|s| s.is_some()).count();
+        let tools = [
+            contacts > 0,
+            contacts == 1,
+            contacts == 2,
+            contacts == 3,
+            contacts == 4,
+            contacts >= 5,
+        ];
+        let pos = match frame.last() {
+            Some(ev) if is_syn_report(ev) => frame.len() - 1,
+            _ => frame.len(),
+        };
+        let mut changed: Vec<input_event> = TOOL_KEYS.iter().zip(tools.iter()).zip(self.tools.iter())
+            .filter(|((_, new), old)| new != old)
+            .map(|((key, new), _)| new_event(sys::EV_KEY, *key, *new as i32))
+            .collect();
+        self.tools = tools;
+
+        // Pointer follows the lowest contact, pressure drops once all are lifted
+        let pointer = match self.slots.iter().position(|s| s.is_some()) {
+            Some(slot) => self.values[slot],
+            None => [None, None, self.pointer[2].map(|_| 0)],
+        };
+        for (idx, (st, _)) in POINTER_AXES.iter().enumerate() {
+            if let Some(value) = pointer[idx] {
+                if self.pointer[idx] != Some(value) {
+                    changed.push(new_event(sys::EV_ABS, *st, value));
+                    self.pointer[idx] = Some(value);
+                }
+            }
+        }
+        frame.splice(pos..pos, changed);
+    }
+
+    /// Lifts contacts of sources for which `present` is false, returns frame
+    /// with those events or nothing if there were no such contacts
+    pub fn release<F: Fn(&str) -> bool>(&mut self, present: F) -> Vec<input_event> {
+        let mut out = Vec::new();
+        for slot in 0..self.slots.len() {
+            if self.slots[slot].as_ref().map_or(false, |(n, _)| !present(n)) {
+                self.select(slot, &mut out);
+                out.push(new_event(sys::EV_ABS, sys::ABS_MT_TRACKING_ID as u16, -1));
+                self.slots[slot] = None;
+            }
+        }
+        self.current.retain(|n, _| present(n));
+        if !out.is_empty() {
+            out.push(syn_report());
+            self.sync_tools(&mut out);
+        }
+        out
+    }
+
+    /// Forgets slot selected on the virtual device, the next event selects
+    /// its slot explicitly. Needed when events go to another device.
+    pub fn reset_slot(&mut self) {
+        self.last_slot = -1;
+    }
+
+    fn select(&mut self, slot: usize, out: &mut Vec<input_event>) {
+        if self.last_slot != slot as i32 {
+            self.last_slot = slot as i32;
+            out.push(new_event(sys::EV_ABS, sys::ABS_MT_SLOT as u16, slot as i32));
+        }
+    }
+}
+
+fn is_mt_axis(code: u16) -> bool {
+    (sys::ABS_MT_SLOT as u16..=sys::ABS_MT_TOOL_Y as u16).contains(&code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn abs(code: i32, value: i32) -> input_event {
+        new_event(sys::EV_ABS, code as u16, value)
+    }
+
+    fn touch(slot: i32, id: i32, x: i32, y: i32) -> Vec<input_event> {
+        vec![
+            abs(sys::ABS_MT_SLOT, slot),
+            abs(sys::ABS_MT_TRACKING_ID, id),
+            abs(sys::ABS_MT_POSITION_X, x),
+            abs(sys::ABS_MT_POSITION_Y, y),
+            abs(sys::ABS_X, x),
+            abs(sys::ABS_Y, y),
+            new_event(sys::EV_KEY, sys::BTN_TOUCH as u16, 1),
+            syn_report(),
+        ]
+    }
+
+    fn lift(slot: i32) -> Vec<input_event> {
+        vec![
+            abs(sys::ABS_MT_SLOT, slot),
+            abs(sys::ABS_MT_TRACKING_ID, -1),
+            new_event(sys::EV_KEY, sys::BTN_TOUCH as u16, 0),
+            syn_report(),
+        ]
+    }
+
+    fn frame(map: &mut SlotMap, source: &str, events: &[input_event]) -> Vec<(u16, u16, i32)> {
+        let mut out = map.remap(source, events);
+        map.sync_tools(&mut out);
+        out.iter().map(|ev| (ev.type_, ev.code, ev.value)).collect()
+    }
+
+    fn value(out: &[(u16, u16, i32)], type_: i32, code: i32) -> Option<i32> {
+        out.iter().rev()
+            .find(|(t, c, _)| *t == type_ as u16 && *c == code as u16)
+            .map(|(_, _, v)| *v)
+    }
+
+    #[test]
+    fn contacts_of_sources_get_own_slots() {
+        let mut map = SlotMap::new(4);
+        let a = frame(&mut map, "a", &touch(0, 7, 10, 20));
+        let b = frame(&mut map, "b", &touch(0, 7, 30, 40));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_MT_SLOT), None);
+        assert_eq!(value(&b, sys::EV_ABS, sys::ABS_MT_SLOT), Some(1));
+        assert_ne!(value(&a, sys::EV_ABS, sys::ABS_MT_TRACKING_ID), value(&b, sys::EV_ABS, sys::ABS_MT_TRACKING_ID));
+    }
+
+    #[test]
+    fn contact_without_free_slot_is_dropped() {
+        let mut map = SlotMap::new(1);
+        frame(&mut map, "a", &touch(0, 1, 10, 20));
+        let b = frame(&mut map, "b", &touch(0, 1, 30, 40));
+        assert_eq!(value(&b, sys::EV_ABS, sys::ABS_MT_TRACKING_ID), None);
+        assert_eq!(value(&b, sys::EV_ABS, sys::ABS_MT_POSITION_X), None);
+
+        frame(&mut map, "a", &lift(0));
+        let b = frame(&mut map, "b", &touch(0, 2, 30, 40));
+        assert_eq!(value(&b, sys::EV_ABS, sys::ABS_MT_POSITION_X), Some(30));
+    }
+
+    #[test]
+    fn removed_source_releases_its_contacts() {
+        let mut map = SlotMap::new(4);
+        frame(&mut map, "a", &touch(0, 1, 10, 20));
+        frame(&mut map, "b", &touch(0, 1, 30, 40));
+        let out: Vec<(u16, u16, i32)> = map.release(|n| n != "a").iter()
+            .map(|ev| (ev.type_, ev.code, ev.value))
+            .collect();
+        assert!(out.contains(&(sys::EV_ABS as u16, sys::ABS_MT_SLOT as u16, 0)));
+        assert_eq!(value(&out, sys::EV_ABS, sys::ABS_MT_TRACKING_ID), Some(-1));
+        assert_eq!(value(&out, sys::EV_KEY, sys::BTN_TOOL_FINGER), Some(1));
+        assert_eq!(value(&out, sys::EV_KEY, sys::BTN_TOUCH), None);
+        assert!(map.release(|n| n != "a").is_empty());
+    }
+
+    #[test]
+    fn tools_follow_contacts_of_all_sources() {
+        let mut map = SlotMap::new(4);
+        let a = frame(&mut map, "a", &touch(0, 1, 10, 20));
+        assert_eq!(value(&a, sys::EV_KEY, sys::BTN_TOUCH), Some(1));
+        assert_eq!(value(&a, sys::EV_KEY, sys::BTN_TOOL_FINGER), Some(1));
+
+        let b = frame(&mut map, "b", &touch(0, 1, 30, 40));
+        assert_eq!(value(&b, sys::EV_KEY, sys::BTN_TOOL_FINGER), Some(0));
+        assert_eq!(value(&b, sys::EV_KEY, sys::BTN_TOOL_DOUBLETAP), Some(1));
+        assert_eq!(value(&b, sys::EV_KEY, sys::BTN_TOUCH), None);
+
+        // Release on one source keeps touch of the other
+        let a = frame(&mut map, "a", &lift(0));
+        assert_eq!(value(&a, sys::EV_KEY, sys::BTN_TOUCH), None);
+        assert_eq!(value(&a, sys::EV_KEY, sys::BTN_TOOL_FINGER), Some(1));
+        let b = frame(&mut map, "b", &lift(0));
+        assert_eq!(value(&b, sys::EV_KEY, sys::BTN_TOUCH), Some(0));
+        assert_eq!(b.last(), Some(&(sys::EV_SYN as u16, sys::SYN_REPORT as u16, 0)));
+    }
+
+    #[test]
+    fn pointer_follows_lowest_slot() {
+        let mut map = SlotMap::new(4);
+        let a = frame(&mut map, "a", &touch(0, 1, 10, 20));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_X), Some(10));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_Y), Some(20));
+
+        // Contact in higher slot does not move pointer
+        let b = frame(&mut map, "b", &touch(0, 1, 30, 40));
+        assert_eq!(value(&b, sys::EV_ABS, sys::ABS_X), None);
+
+        let a = frame(&mut map, "a", &lift(0));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_X), Some(30));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_Y), Some(40));
+    }
+
+    #[test]
+    fn pressure_drops_after_last_contact() {
+        let mut map = SlotMap::new(4);
+        let mut events = touch(0, 1, 10, 20);
+        events.insert(4, abs(sys::ABS_MT_PRESSURE, 50));
+        events.insert(5, abs(sys::ABS_PRESSURE, 50));
+        let a = frame(&mut map, "a", &events);
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_PRESSURE), Some(50));
+        let a = frame(&mut map, "a", &lift(0));
+        assert_eq!(value(&a, sys::EV_ABS, sys::ABS_PRESSURE), Some(0));
+    }
+}
@@ -1,9 +1,13 @@
 use std::fs::{File, OpenOptions};
 use std::io;
 
-use input_linux::{EventKind, InputId, Key, MiscKind, RelativeAxis, UInputHandle};
+use input_linux::{AbsoluteAxis, EventKind, InputId, InputProperty, Key, LedKind, MiscKind, RelativeAxis, UInputHandle};
 use input_linux::sys;
 
+use crate::proxydev::caps::{AbsAxis, Capabilities};
+use crate::proxydev::codes;
+use crate::proxydev::device_simple::KeyProfile;
+
 static KEYBOARD_KEYS: [Key; 106] = [
     // Row 1
     Key::Esc,
@@ -159,79 +163,267 @@ static MOUSE_KEYS: [Key; 18] = [
     Key::ButtonTask,
 ];
 
+static GAMEPAD_KEYS: [Key; 15] = [
+    Key::ButtonSouth,
+    Key::ButtonEast,
+    Key::ButtonC,
+    Key::ButtonNorth,
+    Key::ButtonWest,
+    Key::ButtonZ,
+    Key::ButtonTL,
+    Key::ButtonTR,
+    Key::ButtonTL2,
+    Key::ButtonTR2,
+    Key::ButtonSelect,
+    Key::ButtonStart,
+    Key::ButtonMode,
+    Key::ButtonThumbl,
+    Key::ButtonThumbr,
+];
+
+// Axis, min, max, fuzz, flat; same as xpad driver reports
+static GAMEPAD_AXES: [(AbsoluteAxis, i32, i32, i32, i32); 8] = [
+    // Sticks
+    (AbsoluteAxis::X, -32768, 32767, 16, 128),
+    (AbsoluteAxis::Y, -32768, 32767, 16, 128),
+    (AbsoluteAxis::RX, -32768, 32767, 16, 128),
+    (AbsoluteAxis::RY, -32768, 32767, 16, 128),
+    // Triggers
+    (AbsoluteAxis::Z, 0, 255, 0, 0),
+    (AbsoluteAxis::RZ, 0, 255, 0, 0),
+    // D-pad
+    (AbsoluteAxis::Hat0X, -1, 1, 0, 0),
+    (AbsoluteAxis::Hat0Y, -1, 1, 0, 0),
+];
+
+static TABLET_KEYS: [Key; 9] = [
+    Key::ButtonToolPen,
+    Key::ButtonToolRubber,
+    Key::ButtonToolBrush,
+    Key::ButtonToolPencil,
+    Key::ButtonToolAirbrush,
+    Key::ButtonTouch,
+    Key::ButtonStylus,
+    Key::ButtonStylus2,
+    Key::ButtonStylus3,
+];
+
+static TABLET_AXES: [AbsoluteAxis; 6] = [
+    AbsoluteAxis::X,
+    AbsoluteAxis::Y,
+    AbsoluteAxis::Pressure,
+    AbsoluteAxis::Distance,
+    AbsoluteAxis::TiltX,
+    AbsoluteAxis::TiltY,
+];
+
+static TOUCHPAD_BUTTONS: [Key; 3] = [
+    Key::ButtonLeft,
+    Key::ButtonRight,
+    Key::ButtonMiddle,
+];
+
+static TOUCHPAD_FINGERS: [Key; 6] = [
+    Key::ButtonTouch,
+    Key::ButtonToolFinger,
+    Key::ButtonToolDoubleTap,
+    Key::ButtonToolTripleTap,
+    Key::ButtonToolQuadtap,
+    Key::ButtonToolQuintTap,
+];
+
+static TOUCHPAD_AXES: [AbsoluteAxis; 12] = [
+    AbsoluteAxis::X,
+    AbsoluteAxis::Y,
+    AbsoluteAxis::Pressure,
+    AbsoluteAxis::MultitouchSlot,
+    AbsoluteAxis::MultitouchTouchMajor,
+    AbsoluteAxis::MultitouchTouchMinor,
+    AbsoluteAxis::MultitouchOrientation,
+    AbsoluteAxis::MultitouchPositionX,
+    AbsoluteAxis::MultitouchPositionY,
+    AbsoluteAxis::MultitouchToolType,
+    AbsoluteAxis::MultitouchTrackingId,
+    AbsoluteAxis::MultitouchPressure,
+];
+
+/// Number of slots of virtual touchpad, shared by contacts of all sources
+const TOUCHPAD_SLOTS: i32 = 10;
+
+/// `FF_RUMBLE` from linux/input.h
+const FF_RUMBLE: u16 = 0x50;
+
 static UINPUT_PATH: &str = "/dev/uinput";
 
-pub fn new_uinput_kbd(name: &str, vendor: u16, product: u16) -> io::Result<UInputHandle<File>> {
-    info!("Creating new uinput keyboard");
-    let id = InputId{
-        vendor,
-        product,
-        bustype: sys::BUS_USB,
-        version: 5,
-    };
-    let fd = OpenOptions::new().read(true).write(true).open(UINPUT_PATH)?;
-    let handle = UInputHandle::new(fd);
+static KEYBOARD_LEDS: [LedKind; 5] = [
+    LedKind::NumLock,
+    LedKind::CapsLock,
+    LedKind::ScrollLock,
+    LedKind::Compose,
+    LedKind::Kana,
+];
 
-    for t in &[EventKind::Synchronize, EventKind::Misc, EventKind::Key] {
-        debug!("Setting EvKindBit flag: {:?}", t);
-        handle.set_evbit(*t)?;
-    }
+static MOUSE_AXES: [RelativeAxis; 6] = [
+    RelativeAxis::X,
+    RelativeAxis::Y,
+    RelativeAxis::Wheel,
+    RelativeAxis::HorizontalWheel,
+    RelativeAxis::WheelHiRes,
+    RelativeAxis::HorizontalWheelHiRes,
+];
 
-    for m in &[MiscKind::Scancode] {
-        handle.set_mscbit(*m)?;
+/// All keyboard and consumer control keys, i.e. every `KEY_*` code
+fn full_keyboard_keys() -> Vec<u16> {
+    let mut keys: Vec<u16> = codes::KEYS.iter()
+        .filter(|(name, code)| name.starts_with("KEY_") && *code != Key::Reserved as u16)
+        .map(|(_, code)| *code)
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys
+}
+
+fn keyboard_keys(profile: KeyProfile) -> Vec<u16> {
+    match profile {
+        KeyProfile::Basic => KEYBOARD_KEYS.iter().map(|k| *k as u16).collect(),
+        KeyProfile::Full => full_keyboard_keys(),
     }
+}
 
-    for k in &KEYBOARD_KEYS {
-        debug!("Setting KeyBit flag: {:?}", k);
-        handle.set_keybit(*k)?;
+pub fn kbd_caps(profile: KeyProfile) -> Capabilities {
+    Capabilities {
+        events: vec![EventKind::Synchronize as u16, EventKind::Misc as u16, EventKind::Key as u16,
+            EventKind::Led as u16],
+        keys: keyboard_keys(profile),
+        misc: vec![MiscKind::Scancode as u16],
+        leds: KEYBOARD_LEDS.iter().map(|l| *l as u16).collect(),
+        ..Default::default()
     }
+}
 
-    handle.create(&id, name.as_bytes(), 0, &vec![])?;
-    info!("UInput keyboard device '{:?}'({:?}) successfully created", handle.sys_path()?, handle.evdev_name()?);
-    Ok(handle)
+pub fn mouse_caps() -> Capabilities {
+    Capabilities {
+        events: vec![EventKind::Synchronize as u16, EventKind::Misc as u16, EventKind::Key as u16,
+            EventKind::Relative as u16],
+        keys: MOUSE_KEYS.iter().map(|k| *k as u16).collect(),
+        relative: MOUSE_AXES.iter().map(|r| *r as u16).collect(),
+        misc: vec![MiscKind::Scancode as u16],
+        ..Default::default()
+    }
 }
 
-pub fn new_uinput_mouse(name: &str, vendor: u16, product: u16) -> io::Result<UInputHandle<File>> {
-    info!("Creating new uinput mouse");
-    let id = InputId{
-        vendor,
-        product,
-        bustype: sys::BUS_USB,
-        version: 5,
-    };
-    let fd = OpenOptions::new().read(true).write(true).open(UINPUT_PATH)?;
-    let handle = UInputHandle::new(fd);
+pub fn aio_caps(profile: KeyProfile) -> Capabilities {
+    let mut caps = mouse_caps();
+    caps.keys = keyboard_keys(profile);
+    caps.keys.extend(MOUSE_KEYS.iter().map(|k| *k as u16));
+    caps.events.push(EventKind::Led as u16);
+    caps.leds = KEYBOARD_LEDS.iter().map(|l| *l as u16).collect();
+    caps
+}
 
-    for t in &[EventKind::Synchronize, EventKind::Misc, EventKind::Key,
-        EventKind::Relative] {
-        debug!("Setting EvKindBit flag: {:?}", t);
-        handle.set_evbit(*t)?;
+pub fn gamepad_caps() -> Capabilities {
+    Capabilities {
+        events: vec![EventKind::Synchronize as u16, EventKind::Key as u16, EventKind::Absolute as u16,
+            EventKind::ForceFeedback as u16],
+        keys: GAMEPAD_KEYS.iter().map(|k| *k as u16).collect(),
+        absolute: GAMEPAD_AXES.iter().map(|(axis, minimum, maximum, fuzz, flat)| AbsAxis {
+            axis: *axis as u16,
+            minimum: *minimum,
+            maximum: *maximum,
+            fuzz: *fuzz,
+            flat: *flat,
+            resolution: 0,
+        }).collect(),
+        ff: vec![FF_RUMBLE],
+        ff_effects: 16,
+        ..Default::default()
     }
+}
 
-    for m in &[MiscKind::Scancode] {
-        handle.set_mscbit(*m)?;
+/// Pen tablet capabilities of the source device, absinfo is kept as is
+/// so pressure and tilt keep their resolution
+pub fn tablet_caps(source: &Capabilities) -> Capabilities {
+    let mut caps = Capabilities {
+        events: vec![EventKind::Synchronize as u16, EventKind::Key as u16, EventKind::Absolute as u16],
+        keys: TABLET_KEYS.iter().map(|k| *k as u16)
+            .filter(|k| source.keys.contains(k))
+            .collect(),
+        absolute: source.absolute.iter()
+            .filter(|a| TABLET_AXES.iter().any(|t| *t as u16 == a.axis))
+            .copied()
+            .collect(),
+        ..Default::default()
+    };
+    for k in [Key::ButtonToolPen, Key::ButtonTouch].iter().map(|k| *k as u16) {
+        if !caps.keys.contains(&k) {
+            caps.keys.push(k);
+        }
     }
-
-    for k in &MOUSE_KEYS {
-        debug!("Setting KeyBit flag: {:?}", k);
-        handle.set_keybit(*k)?;
+    if source.misc.contains(&(MiscKind::Serial as u16)) {
+        // Tool serial numbers, used to tell pens apart
+        caps.events.push(EventKind::Misc as u16);
+        caps.misc.push(MiscKind::Serial as u16);
     }
+    // Screen tablets map onto display, others move pointer
+    caps.properties.push(if source.properties.contains(&(InputProperty::Direct as u16)) {
+        InputProperty::Direct as u16
+    } else {
+        InputProperty::Pointer as u16
+    });
+    if !caps.absolute.iter().any(|a| a.axis == AbsoluteAxis::X as u16) {
+        warn!("Source device of tablet has no ABS_X axis, is it a tablet?");
+    }
+    caps
+}
 
-    // Mouse AXIS
-    for r in &[RelativeAxis::X, RelativeAxis::Y, RelativeAxis:: Wheel,
-        RelativeAxis::HorizontalWheel,
-        RelativeAxis::WheelHiRes, RelativeAxis::HorizontalWheelHiRes] {
-        debug!("Setting Relative Axis flag: {:?}", r);
-        handle.set_relbit(*r)?;
+/// Multitouch touchpad capabilities of the source device, slots and tracking
+/// ids are widened to fit contacts of several sources
+pub fn touchpad_caps(source: &Capabilities) -> Capabilities {
+    let mut caps = Capabilities {
+        events: vec![EventKind::Synchronize as u16, EventKind::Key as u16, EventKind::Absolute as u16],
+        keys: TOUCHPAD_BUTTONS.iter().map(|k| *k as u16)
+            .filter(|k| source.keys.contains(k))
+            // Finger count keys are reported by proxy itself, so all of them are needed
+            .chain(TOUCHPAD_FINGERS.iter().map(|k| *k as u16))
+            .collect(),
+        absolute: source.absolute.iter()
+            .filter(|a| TOUCHPAD_AXES.iter().any(|t| *t as u16 == a.axis))
+            .copied()
+            .collect(),
+        properties: source.properties.iter()
+            .filter(|p| [InputProperty::ButtonPad, InputProperty::TopButtonPad].iter().any(|t| *t as u16 == **p))
+            .copied()
+            .collect(),
+        ..Default::default()
+    };
+    caps.properties.push(InputProperty::Pointer as u16);
+    for (axis, maximum) in [
+        (AbsoluteAxis::MultitouchSlot, TOUCHPAD_SLOTS - 1),
+        (AbsoluteAxis::MultitouchTrackingId, 0xffff),
+    ].iter() {
+        let axis = AbsAxis { axis: *axis as u16, minimum: 0, maximum: *maximum, fuzz: 0, flat: 0, resolution: 0 };
+        match caps.absolute.iter_mut().find(|a| a.axis == axis.axis) {
+            Some(a) => *a = axis,
+            None => caps.absolute.push(axis),
+        }
+    }
+    if source.misc.contains(&(MiscKind::Timestamp as u16)) {
+        // Used by libinput to detect jumps
+        caps.events.push(EventKind::Misc as u16);
+        caps.misc.push(MiscKind::Timestamp as u16);
+    }
+    if !caps.absolute.iter().any(|a| a.axis == AbsoluteAxis::MultitouchPositionX as u16) {
+        warn!("Source device of touchpad has no ABS_MT_POSITION_X axis, is it a multitouch touchpad?");
     }
+    caps
+}
 
-    handle.create(&id, name.as_bytes(), 0, &vec![])?;
-    info!("UInput mouse device '{:?}'({:?}) successfully created", handle.sys_path()?, handle.evdev_name()?);
-    Ok(handle)
+fn code_err(kind: &str, code: u16) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {:} code {:}", kind, code))
 }
 
-pub fn new_uinput_aio(name: &str, vendor: u16, product: u16) -> io::Result<UInputHandle<File>> {
-    info!("Creating AIO UInput device '{:}' ({:x}:{:x})", name, vendor, product);
+pub fn new_uinput(name: &str, vendor: u16, product: u16, caps: &Capabilities) -> io::Result<UInputHandle<File>> {
+    info!("Creating UInput device '{:}' ({:x}:{:x})", name, vendor, product);
 
     let id = InputId{
          vendor,
@@ -243,34 +435,49 @@ pub fn new_uinput_aio(name: &str, vendor: u16, product: u16) -> io::Result<UInpu
     let fd = OpenOptions::new().read(true).write(true).open(UINPUT_PATH)?;
     let handle = UInputHandle::new(fd);
 
-    for t in &[EventKind::Synchronize, EventKind::Misc, EventKind::Key,
-            EventKind::Relative] {
+    for t in &caps.events {
+        let t = EventKind::from_type(*t).map_err(|_| code_err("event type", *t))?;
         debug!("Setting EvKindBit flag: {:?}", t);
-        handle.set_evbit(*t)?;
+        handle.set_evbit(t)?;
     }
 
-    for m in &[MiscKind::Scancode] {
-        handle.set_mscbit(*m)?;
+    for m in &caps.misc {
+        handle.set_mscbit(MiscKind::from_code(*m).map_err(|_| code_err("misc", *m))?)?;
     }
 
-    for k in &KEYBOARD_KEYS {
+    for k in &caps.keys {
+        let k = Key::from_code(*k).map_err(|_| code_err("key", *k))?;
         debug!("Setting KeyBit flag: {:?}", k);
-        handle.set_keybit(*k)?;
-    }
-    for k in &MOUSE_KEYS {
-        debug!("Setting Mouse KeyBit flag: {:?}", k);
-        handle.set_keybit(*k)?;
+        handle.set_keybit(k)?;
     }
 
-    // Mouse AXIS
-    for r in &[RelativeAxis::X, RelativeAxis::Y, RelativeAxis:: Wheel,
-            RelativeAxis::HorizontalWheel,
-            RelativeAxis::WheelHiRes, RelativeAxis::HorizontalWheelHiRes] {
+    for r in &caps.relative {
+        let r = RelativeAxis::from_code(*r).map_err(|_| code_err("relative axis", *r))?;
         debug!("Setting Relative Axis flag: {:?}", r);
-        handle.set_relbit(*r)?;
+        handle.set_relbit(r)?;
+    }
+
+    let mut abs = Vec::with_capacity(caps.absolute.len());
+    for a in &caps.absolute {
+        let setup = a.setup()?;
+        debug!("Setting Absolute Axis flag: {:?}", setup);
+        handle.set_absbit(setup.axis)?;
+        abs.push(setup);
+    }
+
+    for l in &caps.leds {
+        handle.set_ledbit(LedKind::from_code(*l).map_err(|_| code_err("led", *l))?)?;
+    }
+
+    for p in &caps.properties {
+        handle.set_propbit(InputProperty::from_code(*p).map_err(|_| code_err("property", *p))?)?;
+    }
+
+    for f in &caps.ff {
+        handle.set_ffbit(*f as i32)?;
     }
 
-    handle.create(&id, name.as_bytes(), 0, &vec![])?;
-    info!("AIO UInput device '{:?}'({:?}) successfully created", handle.sys_path()?, handle.evdev_name()?);
+    handle.create(&id, name.as_bytes(), caps.ff_effects, &abs)?;
+    info!("UInput device '{:?}'({:?}) successfully created", handle.sys_path()?, handle.evdev_name()?);
     Ok(handle)
 }
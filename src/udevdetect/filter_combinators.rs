@@ -0,0 +1,148 @@
+use udev::{Device, Event};
+
+use crate::udevdetect::DevFilter;
+
+type Filters = Vec<Box<dyn DevFilter+Send+Sync>>;
+
+/// Matches devices that match all of filters
+#[derive(Debug)]
+pub struct AllFilter {
+    filters: Filters,
+}
+impl AllFilter {
+    pub fn new(filters: Filters) -> Self {
+        AllFilter { filters }
+    }
+}
+impl DevFilter for AllFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.filters.iter().all(|f| f.match_event(e))
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.filters.iter().all(|f| f.match_device(e))
+    }
+}
+
+/// Matches devices that match any of filters
+#[derive(Debug)]
+pub struct AnyFilter {
+    filters: Filters,
+}
+impl AnyFilter {
+    pub fn new(filters: Filters) -> Self {
+        AnyFilter { filters }
+    }
+}
+impl DevFilter for AnyFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.filters.iter().any(|f| f.match_event(e))
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.filters.iter().any(|f| f.match_device(e))
+    }
+}
+
+/// Matches devices that do not match filter
+#[derive(Debug)]
+pub struct NotFilter {
+    filter: Box<dyn DevFilter+Send+Sync>,
+}
+impl NotFilter {
+    pub fn new(filter: Box<dyn DevFilter+Send+Sync>) -> Self {
+        NotFilter { filter }
+    }
+}
+impl DevFilter for NotFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        !self.filter.match_event(e)
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        !self.filter.match_device(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Gives fixed answer and counts how many times it was asked
+    #[derive(Debug)]
+    struct Fixed {
+        result: bool,
+        calls: Arc<AtomicUsize>,
+    }
+    impl DevFilter for Fixed {
+        fn match_event(&self, e: &Event) -> bool {
+            self.match_device(e)
+        }
+
+        fn match_device(&self, _: &Device) -> bool {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.result
+        }
+    }
+
+    fn fixed(results: &[bool]) -> (Filters, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let filters = results.iter()
+            .map(|&result| Box::new(Fixed { result, calls: calls.clone() }) as Box<dyn DevFilter+Send+Sync>)
+            .collect();
+        (filters, calls)
+    }
+
+    fn device() -> Device {
+        Device::from_syspath(Path::new("/sys/devices/virtual/mem/null")).expect("No /dev/null in sysfs")
+    }
+
+    #[test]
+    fn all_filter() {
+        let dev = device();
+        assert!(AllFilter::new(fixed(&[true, true]).0).match_device(&dev));
+        assert!(!AllFilter::new(fixed(&[true, false]).0).match_device(&dev));
+        assert!(AllFilter::new(Vec::new()).match_device(&dev));
+    }
+
+    #[test]
+    fn any_filter() {
+        let dev = device();
+        assert!(AnyFilter::new(fixed(&[false, true]).0).match_device(&dev));
+        assert!(!AnyFilter::new(fixed(&[false, false]).0).match_device(&dev));
+        assert!(!AnyFilter::new(Vec::new()).match_device(&dev));
+    }
+
+    #[test]
+    fn not_filter() {
+        let dev = device();
+        let (mut filters, _) = fixed(&[true]);
+        assert!(!NotFilter::new(filters.remove(0)).match_device(&dev));
+        let (mut filters, _) = fixed(&[false]);
+        assert!(NotFilter::new(filters.remove(0)).match_device(&dev));
+    }
+
+    #[test]
+    fn evaluation_stops_at_first_decisive_filter() {
+        let dev = device();
+        let (filters, calls) = fixed(&[false, true, true]);
+        AllFilter::new(filters).match_device(&dev);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        let (filters, calls) = fixed(&[false, true, false]);
+        AnyFilter::new(filters).match_device(&dev);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn nested_filters() {
+        let dev = device();
+        let (any, _) = fixed(&[false, true]);
+        let (mut not, _) = fixed(&[false]);
+        let filters: Filters = vec![Box::new(AnyFilter::new(any)), Box::new(NotFilter::new(not.remove(0)))];
+        assert!(AllFilter::new(filters).match_device(&dev));
+    }
+}
@@ -0,0 +1,137 @@
+use input_linux::sys;
+use udev::{Device, Event};
+
+use crate::udevdetect::get_device_property;
+use crate::udevdetect::DevFilter;
+
+/// Bus of input device, as reported in its `id/bustype`
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum InputBus {
+    USB,
+    Bluetooth,
+    /// Laptop and other PS/2 keyboards and touchpads
+    I8042,
+    /// Devices created by uinput and alike
+    Virtual,
+}
+
+impl InputBus {
+    fn code(&self) -> u16 {
+        match self {
+            InputBus::USB => sys::BUS_USB,
+            InputBus::Bluetooth => sys::BUS_BLUETOOTH,
+            InputBus::I8042 => sys::BUS_I8042,
+            InputBus::Virtual => sys::BUS_VIRTUAL,
+        }
+    }
+}
+
+/// Selects device by bus, vendor, product and version of input device, which
+/// are present for non-USB devices too. Unset fields match anything.
+#[derive(Debug)]
+pub struct InputIDFilter {
+    bus: Option<InputBus>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    version: Option<u16>,
+}
+impl InputIDFilter {
+    pub fn new(bus: Option<InputBus>, vendor: Option<u16>, product: Option<u16>, version: Option<u16>) -> Self {
+        let f = InputIDFilter { bus, vendor, product, version };
+        debug!("New InputID Filter for: {:?} {:?}:{:?} ({:?})", f.bus, f.vendor, f.product, f.version);
+        f
+    }
+
+    fn match_id(&self, e: &Device) -> bool {
+        input_id(e).map_or(false, |id| self.match_values(&id))
+    }
+
+    fn match_values(&self, id: &[u16; 4]) -> bool {
+        let wanted = [self.bus.map(|b| b.code()), self.vendor, self.product, self.version];
+        wanted.iter().zip(id.iter()).all(|(w, v)| w.map_or(true, |w| w == *v))
+    }
+}
+impl DevFilter for InputIDFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.match_id(e)
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.match_id(e)
+    }
+}
+
+/// Bus, vendor, product and version from `PRODUCT` property, which looks like
+/// `5/46d/b342/11`, or from `id/*` attributes of parent input device
+fn input_id(e: &Device) -> Option<[u16; 4]> {
+    if let Some(id) = parse_product(get_device_property(e, "PRODUCT")) {
+        return Some(id)
+    }
+    // Event nodes have neither of them, they belong to parent input device
+    let parent = e.parent()?;
+    if let Some(id) = parse_product(get_device_property(&parent, "PRODUCT")) {
+        return Some(id)
+    }
+    let mut id = [0; 4];
+    for (v, attr) in id.iter_mut().zip(["id/bustype", "id/vendor", "id/product", "id/version"].iter()) {
+        *v = parse_id_attr(parent.attribute_value(attr)?.to_str()?)?;
+    }
+    Some(id)
+}
+
+fn parse_product(product: &str) -> Option<[u16; 4]> {
+    let mut id = [0; 4];
+    let mut parts = product.split('/');
+    for v in id.iter_mut() {
+        *v = u16::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    match parts.next() {
+        None => Some(id),
+        Some(_) => None,
+    }
+}
+
+/// Attributes under `id/` hold hex value with trailing newline, e.g. `046d\n`
+fn parse_id_attr(raw: &str) -> Option<u16> {
+    u16::from_str_radix(raw.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn product_is_hex() {
+        assert_eq!(parse_product("5/46d/b342/11"), Some([0x5, 0x46d, 0xb342, 0x11]));
+        assert_eq!(parse_product("3/FEED/BEEF/0"), Some([0x3, 0xfeed, 0xbeef, 0]));
+    }
+
+    #[test]
+    fn malformed_product_is_rejected() {
+        assert_eq!(parse_product(""), None);
+        assert_eq!(parse_product("5/46d/b342"), None);
+        assert_eq!(parse_product("5/46d/b342/11/0"), None);
+        assert_eq!(parse_product("5/46d//11"), None);
+        assert_eq!(parse_product("5/46d/b342/1ffff"), None);
+        assert_eq!(parse_product("5/46g/b342/11"), None);
+    }
+
+    #[test]
+    fn id_attributes() {
+        assert_eq!(parse_id_attr("0003\n"), Some(0x3));
+        assert_eq!(parse_id_attr("046d"), Some(0x46d));
+        assert_eq!(parse_id_attr(""), None);
+        assert_eq!(parse_id_attr("10000\n"), None);
+    }
+
+    #[test]
+    fn unset_fields_match_anything() {
+        let id = [sys::BUS_USB, 0x46d, 0xb342, 0x11];
+        assert!(InputIDFilter::new(None, None, None, None).match_values(&id));
+        assert!(InputIDFilter::new(Some(InputBus::USB), Some(0x46d), None, None).match_values(&id));
+        assert!(InputIDFilter::new(None, None, Some(0xb342), Some(0x11)).match_values(&id));
+        assert!(!InputIDFilter::new(Some(InputBus::Bluetooth), Some(0x46d), None, None).match_values(&id));
+        assert!(!InputIDFilter::new(None, Some(0x46d), Some(0xb343), None).match_values(&id));
+    }
+}
@@ -0,0 +1,124 @@
+use std::io;
+
+use regex::Regex;
+use udev::{Device, Event};
+
+use crate::udevdetect::{DevFilter, get_device_property};
+
+/// Matches device name against glob or regex. Name is taken from `NAME`
+/// property and, for event nodes that lack it, from `name` attribute of
+/// parent input device.
+#[derive(Debug)]
+pub struct NameFilter {
+    pattern: Regex,
+}
+impl NameFilter {
+    pub fn new(pattern: &str, regex: bool) -> io::Result<Self> {
+        let re = if regex { pattern.to_owned() } else { glob_to_regex(pattern) };
+        let f = NameFilter {
+            pattern: Regex::new(&re)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid name pattern '{:}': {:}", pattern, e)))?,
+        };
+        debug!("New Name Filter for: {:?}", f.pattern);
+        Ok(f)
+    }
+
+    fn match_name(&self, e: &Device) -> bool {
+        let name = get_device_property(e, "NAME").trim_matches('"');
+        if !name.is_empty() && self.pattern.is_match(name) {
+            return true
+        }
+        e.parent()
+            .and_then(|p| p.attribute_value("name").and_then(|n| n.to_str()).map(|n| n.trim_end().to_owned()))
+            .map_or(false, |n| self.pattern.is_match(&n))
+    }
+}
+impl DevFilter for NameFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.match_name(e)
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.match_name(e)
+    }
+}
+
+/// Converts glob with `*`, `?` and `[...]` classes to anchored regex
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break
+                    }
+                    // Only ranges keep their meaning, e.g. `&&` is intersection in regex
+                    if c == '-' {
+                        re.push(c);
+                    } else {
+                        re.push_str(&regex::escape(&c.to_string()));
+                    }
+                }
+                re.push(']');
+            },
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, name: &str) -> bool {
+        Regex::new(&glob_to_regex(pattern)).unwrap().is_match(name)
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("Logitech*", "Logitech USB Receiver"));
+        assert!(glob("*Mouse", "Logitech G Pro Mouse"));
+        assert!(!glob("Mouse", "Logitech G Pro Mouse"));
+        assert!(glob("event?", "event7"));
+        assert!(!glob("event?", "event12"));
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert!(glob("event[0-3]", "event2"));
+        assert!(!glob("event[0-3]", "event5"));
+        assert!(glob("event[!0-3]", "event5"));
+        assert!(glob("a[^]b", "a^b"));
+        assert!(!glob("a[^]b", "axb"));
+        assert!(glob("a[&&x]b", "a&b"));
+        assert!(glob("a[.]b", "a.b"));
+        assert!(!glob("a[.]b", "axb"));
+        assert!(glob("a[ _]b", "a b"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_syntax() {
+        assert!(glob("USB (HID) Keyboard", "USB (HID) Keyboard"));
+        assert!(glob("a.b+c$d|e", "a.b+c$d|e"));
+        assert!(!glob("a.b", "axb"));
+        assert!(glob("ELAN\\Touch", "ELAN\\Touch"));
+        assert!(glob("{x}^", "{x}^"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(NameFilter::new("(unclosed", true).is_err());
+        assert!(NameFilter::new("(unclosed", false).is_ok());
+    }
+}
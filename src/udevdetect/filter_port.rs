@@ -0,0 +1,99 @@
+use udev::{Device, Event};
+
+use crate::udevdetect::{DevFilter, get_device_property, get_event_property};
+
+/// Selects devices by physical port, compares `ID_PATH` or `ID_PATH_TAG`.
+/// With `prefix` set, devices behind hub plugged into the port match too.
+#[derive(Debug)]
+pub struct PortFilter {
+    path: String,
+    prefix: bool,
+}
+impl PortFilter {
+    pub fn new(path: &str, prefix: bool) -> Self {
+        let f = PortFilter {
+            path: path.to_owned(),
+            prefix,
+        };
+        debug!("New Port Filter for: {:?} (prefix: {:?})", f.path, f.prefix);
+        f
+    }
+
+    fn match_path(&self, path: &str) -> bool {
+        if path.is_empty() {
+            return false
+        }
+        if !self.prefix {
+            return path == self.path
+        }
+        // Prefix has to end on port boundary, so port 1 does not match port 12
+        let boundary = ['.', ':', '-', '_'];
+        match path.strip_prefix(&self.path) {
+            Some(rest) => rest.is_empty() || rest.starts_with(boundary) || self.path.ends_with(boundary),
+            None => false,
+        }
+    }
+}
+impl DevFilter for PortFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.match_path(get_event_property(e, "ID_PATH")) || self.match_path(get_event_property(e, "ID_PATH_TAG"))
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.match_path(get_device_property(e, "ID_PATH")) || self.match_path(get_device_property(e, "ID_PATH_TAG"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HUB_PORT: &str = "pci-0000:00:14.0-usb-0:1.4:1.0";
+
+    #[test]
+    fn exact_path() {
+        let f = PortFilter::new("pci-0000:00:14.0-usb-0:1.4:1.0", false);
+        assert!(f.match_path(HUB_PORT));
+        assert!(!f.match_path("pci-0000:00:14.0-usb-0:1.4:1.1"));
+        assert!(!f.match_path(""));
+    }
+
+    #[test]
+    fn exact_path_ignores_devices_behind_port() {
+        let f = PortFilter::new("pci-0000:00:14.0-usb-0:1", false);
+        assert!(!f.match_path(HUB_PORT));
+    }
+
+    #[test]
+    fn prefix_ends_on_port_boundary() {
+        let f = PortFilter::new("pci-0000:00:14.0-usb-0:1", true);
+        assert!(f.match_path("pci-0000:00:14.0-usb-0:1"));
+        assert!(f.match_path("pci-0000:00:14.0-usb-0:1:1.0"));
+        assert!(f.match_path(HUB_PORT));
+        assert!(!f.match_path("pci-0000:00:14.0-usb-0:12:1.0"));
+        assert!(!f.match_path("pci-0000:00:14.0-usb-0:2:1.0"));
+    }
+
+    #[test]
+    fn prefix_boundary_in_dashed_paths() {
+        let f = PortFilter::new("platform-xhci-hcd.0-usb-1-2", true);
+        assert!(f.match_path("platform-xhci-hcd.0-usb-1-2"));
+        assert!(f.match_path("platform-xhci-hcd.0-usb-1-2.3"));
+        assert!(!f.match_path("platform-xhci-hcd.0-usb-1-20"));
+        assert!(!f.match_path("platform-xhci-hcd.0-usb-1-21.3"));
+    }
+
+    #[test]
+    fn prefix_boundary_in_path_tags() {
+        let f = PortFilter::new("pci-0000_00_14_0-usb-0_1", true);
+        assert!(f.match_path("pci-0000_00_14_0-usb-0_1_4_1_0"));
+        assert!(!f.match_path("pci-0000_00_14_0-usb-0_10_1_0"));
+    }
+
+    #[test]
+    fn prefix_ending_with_separator() {
+        let f = PortFilter::new("pci-0000:00:14.0-usb-0:1.", true);
+        assert!(f.match_path(HUB_PORT));
+        assert!(!f.match_path("pci-0000:00:14.0-usb-0:1:1.0"));
+    }
+}
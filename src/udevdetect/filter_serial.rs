@@ -0,0 +1,52 @@
+use udev::{Device, Event};
+
+use crate::udevdetect::{DevFilter, get_device_property};
+
+/// Selects device by serial number, compares `ID_SERIAL`, `ID_SERIAL_SHORT`
+/// and `uniq` of input device. Optional vendor:model narrows it further,
+/// since serials are unique only within one model.
+#[derive(Debug)]
+pub struct SerialFilter {
+    serial: String,
+    vendor: Option<String>,
+    model: Option<String>,
+}
+impl SerialFilter {
+    pub fn new(serial: &str, vendor: Option<u16>, model: Option<u16>) -> Self {
+        let f = SerialFilter {
+            serial: serial.to_owned(),
+            vendor: vendor.map(|v| format!("{:04x}", v)),
+            model: model.map(|m| format!("{:04x}", m)),
+        };
+        debug!("New Serial Filter for: {:?} {:?}:{:?}", f.serial, f.vendor, f.model);
+        f
+    }
+
+    fn match_serial(&self, e: &Device) -> bool {
+        if self.vendor.as_ref().map_or(false, |v| get_device_property(e, "ID_VENDOR_ID") != v) {
+            return false
+        }
+        if self.model.as_ref().map_or(false, |m| get_device_property(e, "ID_MODEL_ID") != m) {
+            return false
+        }
+        if get_device_property(e, "ID_SERIAL") == self.serial || get_device_property(e, "ID_SERIAL_SHORT") == self.serial {
+            return true
+        }
+        // Event nodes have no uniq of their own, it belongs to parent input device
+        if get_device_property(e, "UNIQ").trim_matches('"') == self.serial {
+            return true
+        }
+        e.parent()
+            .and_then(|p| p.attribute_value("uniq").and_then(|u| u.to_str()).map(|u| u.trim_end() == self.serial))
+            .unwrap_or(false)
+    }
+}
+impl DevFilter for SerialFilter {
+    fn match_event(&self, e: &Event) -> bool {
+        self.match_serial(e)
+    }
+
+    fn match_device(&self, e: &Device) -> bool {
+        self.match_serial(e)
+    }
+}
@@ -3,7 +3,7 @@ use udev::{Device, Event};
 use crate::udevdetect::{DevFilter, get_device_property, get_event_property};
 
 // FIXME: move to common area?
-#[derive(Debug, Deserialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
 pub enum USBHIDClass {
     Keyboard,
     Mouse,
@@ -1,6 +1,7 @@
 use std::io;
 use std::io::Error;
 use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, RwLock};
 use std::thread;
 
 use crossbeam::channel::{Receiver, Sender};
@@ -11,15 +12,18 @@ use nix::sys::signal::SigSet;
 use crate::udevdetect::{get_device_property, get_event_property};
 use crate::udevdetect::filter::DevFilter;
 
+type FilterList = Vec<Box<dyn DevFilter+Send+Sync>>;
+
 pub struct DevListener {
-    filters: Vec<Box<dyn DevFilter+Send+Sync>>,
+    // Shared with listening thread, so filters could be replaced while it runs
+    filters: Arc<RwLock<FilterList>>,
     subsystem: String,
     event_queue_size: usize,
 }
 
 #[derive(Debug,PartialEq)]
 pub enum DevEventType {
-    UNKNOWN,
+    Unknown,
     Add,
     Remove,
 }
@@ -31,7 +35,7 @@ impl From<&str> for DevEventType {
         } else if action_raw == "remove" {
             DevEventType::Remove
         } else {
-            DevEventType::UNKNOWN
+            DevEventType::Unknown
         }
     }
 }
@@ -46,42 +50,46 @@ pub struct DevEvent {
     pub input_class: String,
     pub devpath: String,
     pub devname: String,
+    /// Index of the first filter that matched device. Removals are reported
+    /// even if nothing matches, as parent of removed device is already gone.
+    pub filter: Option<usize>,
 }
 
 impl DevListener {
     pub fn new(subsystem: &str, queue_size: usize) -> DevListener {
         DevListener{
             subsystem: subsystem.to_owned(),
-            filters: Vec::new(),
+            filters: Arc::new(RwLock::new(Vec::new())),
             event_queue_size: queue_size,
         }
     }
 
     pub fn add_filter(&mut self, filter: Box<dyn DevFilter+Send+Sync>) {
-        self.filters.push(filter);
+        self.filters.write().unwrap().push(filter);
     }
 
-    pub fn listen(self) -> io::Result<Receiver<DevEvent>> {
-        let (sender, receiver): (Sender<DevEvent>, Receiver<DevEvent>) = channel::bounded(self.event_queue_size);
+    /// Replaces filters of running listener, returns present devices that
+    /// match new filters
+    pub fn set_filters(&self, filters: FilterList) -> Vec<DevEvent> {
+        *self.filters.write().unwrap() = filters;
+        self.scan()
+    }
 
+    /// Present devices that match filters, reported as added ones
+    pub fn scan(&self) -> Vec<DevEvent> {
         info!("Listing present devices for subsystem '{:}'", self.subsystem);
         let mut enumerator = udev::Enumerator::new().unwrap();
         enumerator.match_subsystem(&self.subsystem).unwrap();
         let dev_iter = enumerator.scan_devices().unwrap();
-        let devices: Vec<DevEvent> = dev_iter.filter_map(|dev| -> Option<DevEvent> {
+        dev_iter.filter_map(|dev| -> Option<DevEvent> {
             // Skip devices without node
-            if get_device_property(&dev, "DEVNAME") == "" {
+            if get_device_property(&dev, "DEVNAME").is_empty() {
                 debug!("Skipping device '{:?}', no devname property", dev.syspath());
                 return None
             }
 
-            let mut matched = false;
-            for f in self.filters.iter() {
-                if f.match_device(&dev) {
-                    matched = true;
-                }
-            }
-            if !matched {
+            let matched = self.filters.read().unwrap().iter().position(|f| f.match_device(&dev));
+            if matched.is_none() {
                 debug!("Skipping device '{:?}, do not match any filters", dev.syspath());
                 return None
             }
@@ -110,16 +118,24 @@ impl DevListener {
                 name: name_raw.to_owned(),
                 vendor: vendor_raw.to_owned(),
                 product: model_raw.to_owned(),
+                filter: matched,
             })
-        }).collect();
+        }).collect()
+    }
+
+    /// Listening thread stops after receiver is dropped
+    pub fn listen(&self) -> io::Result<Receiver<DevEvent>> {
+        let (sender, receiver): (Sender<DevEvent>, Receiver<DevEvent>) = channel::bounded(self.event_queue_size);
 
+        let devices = self.scan();
         {
-            let devices = devices;
             let sender = sender.clone();
 
             thread::spawn(move || {
                 for d in devices {
-                    sender.send(d).unwrap();
+                    if sender.send(d).is_err() {
+                        break
+                    }
                 }
             });
         }
@@ -131,7 +147,7 @@ impl DevListener {
             // let mut err_sender = err_sender.clone();
             let sender = sender.clone();
             let subsystem = self.subsystem.to_owned();
-            let filters = self.filters;
+            let filters = self.filters.clone();
 
             thread::spawn(move || {
                 let mut udevmonbuilder;
@@ -146,7 +162,7 @@ impl DevListener {
                             return
                         },
                     };
-                    udevmonbuilder = match udevmonbuilder.match_subsystem(subsystem) {
+                    udevmonbuilder = match udevmonbuilder.match_subsystem(&subsystem) {
                         Ok(m) => m,
                         Err(e) => {
                             err.send(e).unwrap();
@@ -164,7 +180,11 @@ impl DevListener {
                 };
 
                 'event: loop {
-                    ppoll(&mut [fd], None, SigSet::empty()).unwrap();
+                    // Interrupted by signal, e.g. SIGHUP that reloads config
+                    if let Err(e) = ppoll(&mut [fd], None, SigSet::empty()) {
+                        debug!("Failed to poll udev monitor: {:?}", e);
+                        continue 'event;
+                    }
 
                     let event = match udevmon.next() {
                         Some(evt) => evt,
@@ -183,47 +203,41 @@ impl DevListener {
                     let model_raw = get_event_property(&event, "ID_MODEL_ID");
 
                     // Skip devices without node
-                    if devname_raw == "" {
+                    if devname_raw.is_empty() {
                         debug!("Skipping event for '{:?}', no devname property", event.syspath());
                         continue 'event
                     }
 
-                    let mut matched = false;
-                    for f in filters.iter() {
-                        if f.match_event(&event) {
-                            matched = true;
-                        }
-                    }
-                    if !matched {
+                    let action = DevEventType::from(action_raw);
+                    let matched = filters.read().unwrap().iter().position(|f| f.match_event(&event));
+                    if matched.is_none() && action != DevEventType::Remove {
                         debug!("Skipping event for '{:?}, do not match any filters", event.syspath());
                         continue 'event
                     }
 
                     debug!("Emitting event for '{:?}'", event.syspath());
                     let dev_event = DevEvent {
-                        action: DevEventType::from(action_raw),
+                        action,
                         devpath: devpath_raw.to_owned(),
                         devname: devname_raw.to_owned(),
                         input_class: input_class_raw.to_owned(),
                         name: name_raw.to_owned(),
                         vendor: vendor_raw.to_owned(),
                         product: model_raw.to_owned(),
+                        filter: matched,
                     };
 
-                    match sender.send(dev_event) {
-                        Err(e) => {
-                            error!("Failed to send udev event for '{:?}': {:?}", event.syspath(), e);
-                        }
-                        Ok(_) => {},
+                    if sender.send(dev_event).is_err() {
+                        info!("Stopping listening thread for subsystem '{:}'", subsystem);
+                        return
                     }
                 }
             });
 
             // Check for error after event listener init
-            match err_receiver.recv() {
-                Ok(err) => return Err(err),
-                Err(_) => {},
-            };
+            if let Ok(err) = err_receiver.recv() {
+                return Err(err)
+            }
         }
 
         std::mem::drop(sender);
@@ -9,11 +9,22 @@ pub use self::filter::DevFilter;
 pub use self::filter_usbid::USBIDFilter;
 pub use self::filter_usbidclass::USBIDClassFilter;
 pub use self::filter_usbidclass::USBHIDClass;
+pub use self::filter_name::NameFilter;
+pub use self::filter_port::PortFilter;
+pub use self::filter_serial::SerialFilter;
+pub use self::filter_inputid::InputIDFilter;
+pub use self::filter_inputid::InputBus;
+pub use self::filter_combinators::{AllFilter, AnyFilter, NotFilter};
 
 mod listener;
 mod filter;
 mod filter_usbid;
 mod filter_usbidclass;
+mod filter_name;
+mod filter_port;
+mod filter_serial;
+mod filter_inputid;
+mod filter_combinators;
 
 fn get_event_property<'a>(ev: &'a Event, key: &'a str) -> &'a str {
     ev.property_value(key).unwrap_or(OsStr::new("")).to_str().unwrap_or("")